@@ -0,0 +1,242 @@
+use std::{collections::HashMap, str::FromStr};
+
+use actix_web::{
+    dev::ServiceRequest,
+    http::header,
+    HttpMessage, HttpResponse,
+};
+use serde_json::json;
+
+use crate::routes::{self, Route};
+
+// roles of the api keys, every role can do what the previous ones can
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    // the GET requests of the data, the events and the metrics
+    Reader,
+    // the new entities and the import
+    Writer,
+    // the /admin routes
+    Admin,
+}
+
+impl Role {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::Reader => "reader",
+            Role::Writer => "writer",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reader" => Ok(Role::Reader),
+            "writer" => Ok(Role::Writer),
+            "admin" => Ok(Role::Admin),
+            _ => Err(()),
+        }
+    }
+}
+
+// the key of the request, kept in the request extensions for the audit log
+#[derive(Debug, Clone)]
+pub struct Caller {
+    pub name: String,
+    pub role: Role,
+}
+
+// the api keys by their values. the keys are configured
+// as <name>:<role>:<key>, comma-separated, the requests
+// aren't authenticated if there are no keys
+#[derive(Default)]
+pub struct Keys(HashMap<String, Caller>);
+
+impl Keys {
+    pub fn is_enabled(&self) -> bool {
+        !self.0.is_empty()
+    }
+}
+
+impl FromStr for Keys {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut keys = HashMap::new();
+
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let mut parts = entry.splitn(3, ':');
+            let (Some(name), Some(role), Some(key)) = (parts.next(), parts.next(), parts.next()) else {
+                return Err(());
+            };
+            if name.is_empty() || key.is_empty() {
+                return Err(());
+            }
+
+            let caller = Caller {
+                name: name.to_string(),
+                role: role.parse()?,
+            };
+            if keys.insert(key.to_string(), caller).is_some() {
+                return Err(());
+            }
+        }
+
+        Ok(Keys(keys))
+    }
+}
+
+// the role the request needs by the route it goes to, see routes.rs.
+// the paths of no route get 404, they are for the readers
+fn required_role(route: Option<&Route>) -> Option<Role> {
+    match route {
+        Some(route) => route.role,
+        None => Some(Role::Reader),
+    }
+}
+
+// the key of the Authorization: Bearer or the X-Api-Key header
+fn request_key(req: &ServiceRequest) -> Option<&str> {
+    let headers = req.headers();
+
+    if let Some(value) = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok()) {
+        return value.strip_prefix("Bearer ").map(str::trim);
+    }
+
+    headers.get("X-Api-Key").and_then(|v| v.to_str().ok())
+}
+
+// authenticates the request and checks the role of its key,
+// the caller is added to the request extensions. returns the 401 or 403 response
+pub fn check(keys: &Keys, req: &ServiceRequest) -> Result<(), Box<HttpResponse>> {
+    if !keys.is_enabled() {
+        return Ok(());
+    }
+
+    let Some(role) = required_role(routes::of(req)) else {
+        return Ok(());
+    };
+
+    let caller = match request_key(req).and_then(|key| keys.0.get(key)) {
+        Some(caller) => caller,
+        None => {
+            return Err(Box::new(HttpResponse::Unauthorized()
+                .insert_header(header::ContentType::json())
+                .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+                .body(json!({ "error": "missing or unknown api key" }).to_string())))
+        }
+    };
+
+    if caller.role < role {
+        return Err(Box::new(HttpResponse::Forbidden()
+            .insert_header(header::ContentType::json())
+            .body(json!({ "error": format!("the {} role is required", role.name()) }).to_string())));
+    }
+
+    req.extensions_mut().insert(caller.clone());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use actix_web::{
+        dev::Service,
+        http::StatusCode,
+        test::{self, TestRequest},
+        App,
+    };
+    use futures_util::future;
+
+    use super::*;
+
+    fn keys() -> Keys {
+        "ann:reader:r-key, bob:writer:w-key, cat:admin:a-key".parse().unwrap()
+    }
+
+    #[test]
+    fn parses_keys() {
+        let keys = keys();
+        assert!(keys.is_enabled());
+        assert_eq!(keys.0["w-key"].name, "bob");
+        assert_eq!(keys.0["a-key"].role, Role::Admin);
+
+        // the keys may have colons
+        let keys: Keys = "ann:reader:a:b".parse().unwrap();
+        assert_eq!(keys.0["a:b"].name, "ann");
+
+        assert!(!"".parse::<Keys>().unwrap().is_enabled());
+        assert!("ann:reader".parse::<Keys>().is_err());
+        assert!("ann:root:key".parse::<Keys>().is_err());
+        assert!(":reader:key".parse::<Keys>().is_err());
+        assert!("ann:reader:".parse::<Keys>().is_err());
+        assert!("ann:reader:key,bob:admin:key".parse::<Keys>().is_err());
+    }
+
+    #[test]
+    fn roles_of_the_routes() {
+        let role = |name| required_role(routes::ROUTES.iter().find(|r| r.name == name));
+
+        assert_eq!(role("health"), None);
+        assert_eq!(role("ready"), None);
+        assert_eq!(role("user"), Some(Role::Reader));
+        assert_eq!(role("admin_options"), Some(Role::Reader));
+        assert_eq!(role("new_visit"), Some(Role::Writer));
+        assert_eq!(role("import"), Some(Role::Writer));
+        assert_eq!(role("admin_export"), Some(Role::Admin));
+        assert_eq!(role("admin_reload"), Some(Role::Admin));
+        assert_eq!(required_role(None), Some(Role::Reader));
+    }
+
+    // the status of the request with the key, 200 if it's allowed
+    async fn status(method: &str, uri: &str, key: &str) -> StatusCode {
+        let keys = Rc::new(keys());
+        let app = test::init_service(App::new().configure(routes::configure).wrap_fn(
+            move |req, _srv| {
+                let res = match check(&keys, &req) {
+                    Ok(()) => HttpResponse::Ok().finish(),
+                    Err(res) => *res,
+                };
+                future::ready(Ok(req.into_response(res)))
+            },
+        ))
+        .await;
+
+        let req = TestRequest::default()
+            .method(method.parse().unwrap())
+            .uri(uri)
+            .insert_header(("X-Api-Key", key))
+            .to_request();
+        app.call(req).await.unwrap().status()
+    }
+
+    #[actix_web::test]
+    async fn checks_the_role_of_the_decoded_path() {
+        assert_eq!(status("GET", "/admin/export", "a-key").await, StatusCode::OK);
+        assert_eq!(status("GET", "/admin/export", "r-key").await, StatusCode::FORBIDDEN);
+        assert_eq!(status("GET", "/%61dmin/export", "r-key").await, StatusCode::FORBIDDEN);
+        assert_eq!(status("POST", "/%61dmin/%72eload", "w-key").await, StatusCode::FORBIDDEN);
+        assert_eq!(status("POST", "/users/%6Eew", "r-key").await, StatusCode::FORBIDDEN);
+        assert_eq!(status("POST", "/users/%6Eew", "w-key").await, StatusCode::OK);
+        assert_eq!(status("GET", "/%68ealth", "").await, StatusCode::OK);
+        assert_eq!(status("GET", "/users/1", "").await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn checks_the_role_of_the_method() {
+        // GET /users/new goes to /users/{id} and gets 404 like without the keys
+        assert_eq!(status("GET", "/users/new", "r-key").await, StatusCode::OK);
+        assert_eq!(status("POST", "/users/new", "r-key").await, StatusCode::FORBIDDEN);
+        assert_eq!(status("GET", "/admin/reload", "w-key").await, StatusCode::FORBIDDEN);
+        assert_eq!(status("POST", "/admin/reload", "a-key").await, StatusCode::OK);
+        // a path of a route with another method is for the readers
+        assert_eq!(status("POST", "/health", "").await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status("POST", "/users/1", "r-key").await, StatusCode::OK);
+    }
+}
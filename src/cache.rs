@@ -0,0 +1,170 @@
+use std::sync::RwLock;
+
+use bytes::Bytes;
+
+use crate::{idvec::IdVec, render, storage::Storage};
+
+// pre-rendered json responses of the entities,
+// so GET of an entity is a lookup instead of a serialization.
+// responses missing in the cache have to be rendered
+pub struct ResponseCache {
+    budget: usize,
+    entries: RwLock<Entries>,
+}
+
+#[derive(Default)]
+struct Entries {
+    users: IdVec<Bytes>,
+    locations: IdVec<Bytes>,
+    visits: IdVec<Bytes>,
+
+    // approximate memory taken by the cached responses
+    size: usize,
+}
+
+impl ResponseCache {
+    // budget_mb is the memory budget of the cache in megabytes,
+    // 0 disables the cache
+    pub fn new(budget_mb: usize) -> Self {
+        ResponseCache {
+            budget: budget_mb * 1024 * 1024,
+            entries: RwLock::new(Entries::default()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.budget > 0
+    }
+
+    pub fn user(&self, id: u32) -> Option<Bytes> {
+        self.entries.read().unwrap().users.get(id).cloned()
+    }
+
+    pub fn location(&self, id: u32) -> Option<Bytes> {
+        self.entries.read().unwrap().locations.get(id).cloned()
+    }
+
+    pub fn visit(&self, id: u32) -> Option<Bytes> {
+        self.entries.read().unwrap().visits.get(id).cloned()
+    }
+
+    // renders responses of all the entities of the storage,
+    // entities which don't fit into the budget are rendered on request
+    pub fn rebuild(&self, s: &dyn Storage) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let mut e = Entries::default();
+
+        for id in s.user_ids() {
+            let body = render::user(s, id);
+            put(&mut e.users, &mut e.size, self.budget, id, body);
+        }
+        for id in s.location_ids() {
+            let body = render::location(s, id);
+            put(&mut e.locations, &mut e.size, self.budget, id, body);
+        }
+        for id in s.visit_ids() {
+            let body = render::visit(s, id);
+            put(&mut e.visits, &mut e.size, self.budget, id, body);
+        }
+
+        *self.entries.write().unwrap() = e;
+    }
+
+    // re-renders the cached response of the user,
+    // must be called under the storage write lock after the user is changed
+    pub fn refresh_user(&self, s: &dyn Storage, id: u32) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let body = render::user(s, id);
+        let e = &mut *self.entries.write().unwrap();
+        put(&mut e.users, &mut e.size, self.budget, id, body);
+    }
+
+    pub fn refresh_location(&self, s: &dyn Storage, id: u32) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let body = render::location(s, id);
+        let e = &mut *self.entries.write().unwrap();
+        put(&mut e.locations, &mut e.size, self.budget, id, body);
+    }
+
+    pub fn refresh_visit(&self, s: &dyn Storage, id: u32) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let body = render::visit(s, id);
+        let e = &mut *self.entries.write().unwrap();
+        put(&mut e.visits, &mut e.size, self.budget, id, body);
+    }
+
+    // approximate memory taken by the cached responses in bytes
+    pub fn size(&self) -> usize {
+        self.entries.read().unwrap().size
+    }
+}
+
+// the size counts the bodies and the memory of the vectors, a page of the
+// vector is allocated for the first id in it, so with the sparse ids
+// the pages take more memory than the bodies
+fn put(bodies: &mut IdVec<Bytes>, size: &mut usize, budget: usize, id: u32, body: Option<Bytes>) {
+    // the old response is stale in any case,
+    // the memory of the vector isn't freed
+    if let Some(old) = bodies.remove(id) {
+        *size -= old.len();
+    }
+
+    let body = match body {
+        Some(body) => body,
+        None => return,
+    };
+
+    if *size + body.len() + bodies.insert_size(id) > budget {
+        return;
+    }
+
+    let allocated = bodies.heap_size();
+    *size += body.len();
+    bodies.insert(id, body);
+    *size += bodies.heap_size() - allocated;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_the_pages_in_the_budget() {
+        let budget = 1024 * 1024;
+        let mut bodies = IdVec::new();
+        let mut size = 0;
+
+        // every id allocates a page of the vector
+        for i in 0..1000 {
+            put(&mut bodies, &mut size, budget, i * 1024, Some(Bytes::from_static(b"{}")));
+        }
+
+        assert!(size <= budget);
+        assert_eq!(size, bodies.heap_size() + 2 * bodies.len());
+        assert!(bodies.len() < 40, "{} bodies in the budget", bodies.len());
+
+        // the refresh of the cached ids doesn't grow the size
+        let cached = bodies.len();
+        for i in 0..cached as u32 {
+            put(&mut bodies, &mut size, budget, i * 1024, Some(Bytes::from_static(b"{1}")));
+        }
+        assert_eq!(bodies.len(), cached);
+        assert_eq!(size, bodies.heap_size() + 3 * cached);
+
+        put(&mut bodies, &mut size, budget, 0, None);
+        assert_eq!(bodies.len(), cached - 1);
+        assert_eq!(size, bodies.heap_size() + 3 * (cached - 1));
+    }
+}
@@ -0,0 +1,476 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    env, fs,
+    path::PathBuf,
+    str::FromStr,
+};
+
+use crate::{auth, logging, options::RunMode, wal::FsyncPolicy};
+
+// default memory budget of the pre-rendered responses cache,
+// the data of the test runs is small and fits into the smaller one
+const DEFAULT_RESPONSE_CACHE_MB: usize = 512;
+const DEFAULT_TEST_RESPONSE_CACHE_MB: usize = 64;
+
+// default interval of the write-ahead log fsync
+const DEFAULT_WAL_FSYNC_MS: &str = "100";
+
+const DEFAULT_SNAPSHOT_KEEP: &str = "3";
+
+const DEFAULT_BIND: &str = "127.0.0.1:8080";
+
+const DEFAULT_DATA_PATH: &str = "tmp/data/data.zip";
+
+// default number of the recent change events kept for resuming clients
+const DEFAULT_EVENTS_BUFFER: &str = "10000";
+// the buffer is allocated at the start
+const MAX_EVENTS_BUFFER: usize = 1_000_000;
+
+// the defaults of actix-web except the keep-alive
+const DEFAULT_KEEP_ALIVE_S: &str = "30";
+const DEFAULT_BACKLOG: &str = "2048";
+const DEFAULT_MAX_CONNECTIONS: &str = "25000";
+const DEFAULT_JSON_LIMIT_KB: &str = "2048";
+const DEFAULT_IMPORT_LIMIT_MB: &str = "256";
+
+const DEFAULT_SHUTDOWN_TIMEOUT_S: &str = "30";
+
+// the settings, every one is read from the command line flag --<key>
+// (with - instead of _), the environment variable HLCUP_<KEY>
+// or the key of the toml config file, in this order
+const KEYS: &[&str] = &[
+    "bind",
+    "workers",
+    "keep_alive_s",
+    "backlog",
+    "max_connections",
+    "json_limit_kb",
+    "import_limit_mb",
+    "shutdown_timeout_s",
+    "log_level",
+    "log_format",
+    "access_log",
+    "access_log_sample",
+    "data",
+    "options",
+    "reload_dirs",
+    "strict_load",
+    "response_cache_mb",
+    "warmup",
+    "storage",
+    "mmap_dir",
+    "wal",
+    "wal_fsync",
+    "snapshot_dir",
+    "snapshot_interval_s",
+    "snapshot_keep",
+    "events_buffer",
+    "leader",
+    "leader_api_key",
+    "api_keys",
+];
+
+// the values of these settings aren't printed
+const SECRET_KEYS: &[&str] = &["leader_api_key", "api_keys"];
+
+pub struct Config {
+    // addresses the server listens on, comma-separated in the flag and the variable
+    pub bind: Vec<String>,
+    // number of the http worker threads, the number of the cpu cores if it's not set
+    pub workers: Option<usize>,
+    // 0 disables the keep-alive
+    pub keep_alive_s: u64,
+    // maximum number of the pending connections
+    pub backlog: u32,
+    // maximum number of the connections per worker
+    pub max_connections: usize,
+    // maximum size of the json body of the new entities
+    pub json_limit_kb: usize,
+    // maximum size of the POST /import body, 0 for no limit
+    pub import_limit_mb: usize,
+    // deadline of the graceful shutdown after SIGTERM or SIGINT: draining
+    // the requests in flight, syncing the log and writing the final snapshot
+    pub shutdown_timeout_s: u64,
+
+    // error, warn, info or debug
+    pub log_level: logging::Level,
+    // text, logfmt or json
+    pub log_format: logging::Format,
+    // a record per request, see logging::access
+    pub access_log: bool,
+    // every n-th GET request of the entities is logged
+    pub access_log_sample: u64,
+
+    // initial data: a zip or tar.gz archive, or a directory of json files
+    pub data_path: PathBuf,
+    // options.txt with the timestamp of the data,
+    // options.txt next to the archive or in the directory if it's not set
+    pub options_path: Option<PathBuf>,
+    // directories the data and options of POST /admin/reload may be in,
+    // comma-separated. only the configured data can be reloaded if it's empty
+    pub reload_dirs: Vec<PathBuf>,
+    // the server doesn't start if the storage rejects any entity of the data
+    pub strict_load: bool,
+
+    // memory budget of the pre-rendered responses cache, 0 disables the cache.
+    // depends on the run mode of the data if it's not set
+    pub response_cache_mb: Option<usize>,
+
+    // whether to run the queries of all the entities once before serving,
+    // by default only in the rating mode
+    pub warmup: Option<bool>,
+
+    pub storage: StorageKind,
+    // directory of the memory-mapped storage files
+    pub mmap_dir: PathBuf,
+
+    // path of the write-ahead log of the mutations, the log is disabled if it's not set
+    pub wal_path: Option<PathBuf>,
+    pub wal_fsync: FsyncPolicy,
+
+    // directory of the binary snapshots of the in-memory storage,
+    // snapshots are disabled if it's not set
+    pub snapshot_dir: Option<PathBuf>,
+    // interval of the periodic snapshots in seconds, 0 for snapshots on demand only
+    pub snapshot_interval_s: u64,
+    // number of the newest snapshots kept in the directory, at least 1
+    pub snapshot_keep: usize,
+
+    // number of the recent change events kept for the clients resuming with Last-Event-ID,
+    // at most MAX_EVENTS_BUFFER
+    pub events_buffer: usize,
+
+    // address (host:port) of the leader, the server is its read-only follower if it's set
+    pub leader: Option<String>,
+    // the key the follower authenticates to the leader with, a reader one is enough
+    pub leader_api_key: Option<String>,
+
+    // <name>:<role>:<key>, comma-separated, the roles are reader, writer and admin.
+    // the requests aren't authenticated if it's empty, see auth.rs
+    pub api_keys: auth::Keys,
+
+    // --print-config: print the settings and exit
+    pub print_config: bool,
+    // the values of the settings with their origins, see print
+    settings: Vec<(&'static str, Option<String>, String)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StorageKind {
+    Memory,
+    Mmap,
+}
+
+impl std::str::FromStr for StorageKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(StorageKind::Memory),
+            "mmap" => Ok(StorageKind::Mmap),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Config {
+    // reads the configuration from the command line arguments, the environment
+    // variables and the toml file of --config or HLCUP_CONFIG.
+    // returns the rest of the arguments, e.g. the subcommand
+    pub fn load(args: Vec<String>) -> Result<(Self, Vec<String>), String> {
+        // the variables which aren't unicode are ignored, like with env::var
+        let env = env::vars_os()
+            .filter_map(|(key, value)| Some((key.into_string().ok()?, value.into_string().ok()?)))
+            .collect();
+        Self::load_with_env(args, env)
+    }
+
+    // the same with the given environment variables
+    fn load_with_env(args: Vec<String>, env: HashMap<String, String>) -> Result<(Self, Vec<String>), String> {
+        let mut rest = Vec::new();
+        let mut config_path = env.get("HLCUP_CONFIG").map(PathBuf::from);
+        let mut src = Source {
+            env,
+            ..Source::default()
+        };
+        let mut print_config = false;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (flag, value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+                None => (arg.clone(), None),
+            };
+            let key = flag.trim_start_matches("--").replace('-', "_");
+
+            match flag.as_str() {
+                "--print-config" => print_config = true,
+                // a switch, the value is only taken with =
+                "--strict" => {
+                    let value = value.unwrap_or_else(|| "true".to_string());
+                    src.flags.insert("strict_load".to_string(), value);
+                }
+                "--config" => {
+                    let value = value.or_else(|| args.next()).ok_or("--config needs a value")?;
+                    config_path = Some(value.into());
+                }
+                _ if flag.starts_with("--") && KEYS.contains(&key.as_str()) => {
+                    let value = value
+                        .or_else(|| args.next())
+                        .ok_or_else(|| format!("{} needs a value", flag))?;
+                    src.flags.insert(key, value);
+                }
+                _ => rest.push(arg),
+            }
+        }
+
+        if let Some(path) = config_path {
+            src.read_file(path)?;
+        }
+
+        let config = Config {
+            bind: src
+                .var::<String>("bind", DEFAULT_BIND)?
+                .split(',')
+                .map(|addr| addr.trim().to_string())
+                .filter(|addr| !addr.is_empty())
+                .collect(),
+            workers: src.opt("workers")?,
+            keep_alive_s: src.var("keep_alive_s", DEFAULT_KEEP_ALIVE_S)?,
+            backlog: src.var("backlog", DEFAULT_BACKLOG)?,
+            max_connections: src.var("max_connections", DEFAULT_MAX_CONNECTIONS)?,
+            json_limit_kb: src.var("json_limit_kb", DEFAULT_JSON_LIMIT_KB)?,
+            import_limit_mb: src.var("import_limit_mb", DEFAULT_IMPORT_LIMIT_MB)?,
+            shutdown_timeout_s: src.var("shutdown_timeout_s", DEFAULT_SHUTDOWN_TIMEOUT_S)?,
+            log_level: src.var("log_level", "info")?,
+            log_format: src.var("log_format", "text")?,
+            access_log: src.var("access_log", "false")?,
+            access_log_sample: src.var("access_log_sample", "1")?,
+            data_path: src.var("data", DEFAULT_DATA_PATH)?,
+            options_path: src.opt("options")?,
+            reload_dirs: src
+                .var::<String>("reload_dirs", "")?
+                .split(',')
+                .map(|dir| dir.trim())
+                .filter(|dir| !dir.is_empty())
+                .map(PathBuf::from)
+                .collect(),
+            strict_load: src.var("strict_load", "false")?,
+            response_cache_mb: src.opt("response_cache_mb")?,
+            warmup: src.opt("warmup")?,
+            storage: src.var("storage", "memory")?,
+            mmap_dir: src.var("mmap_dir", "mmap")?,
+            wal_path: src.opt("wal")?,
+            wal_fsync: src.var("wal_fsync", DEFAULT_WAL_FSYNC_MS)?,
+            snapshot_dir: src.opt("snapshot_dir")?,
+            snapshot_interval_s: src.var("snapshot_interval_s", "0")?,
+            snapshot_keep: src.var("snapshot_keep", DEFAULT_SNAPSHOT_KEEP)?,
+            events_buffer: src.var("events_buffer", DEFAULT_EVENTS_BUFFER)?,
+            leader: src.opt("leader")?,
+            leader_api_key: src.opt("leader_api_key")?,
+            api_keys: src.var("api_keys", "")?,
+            print_config,
+            settings: src.used.into_inner(),
+        };
+
+        if config.bind.is_empty() {
+            return Err("no bind addresses".to_string());
+        }
+        if config.workers == Some(0) {
+            return Err("workers must be at least 1".to_string());
+        }
+        if config.events_buffer > MAX_EVENTS_BUFFER {
+            return Err(format!("events_buffer must be at most {}", MAX_EVENTS_BUFFER));
+        }
+        if config.snapshot_keep == 0 {
+            return Err("snapshot_keep must be at least 1".to_string());
+        }
+
+        Ok((config, rest))
+    }
+
+    // prints the settings in the format of the config file, with their origins
+    pub fn print(&self) {
+        for (key, value, origin) in &self.settings {
+            match value {
+                Some(value) if SECRET_KEYS.contains(key) && !value.is_empty() => {
+                    println!("{} = \"***\" # {}", key, origin)
+                }
+                Some(value) => println!("{} = {:?} # {}", key, value, origin),
+                None => println!("# {} is not set", key),
+            }
+        }
+    }
+
+    pub fn response_cache_mb(&self, mode: RunMode) -> usize {
+        self.response_cache_mb.unwrap_or(match mode {
+            RunMode::Test => DEFAULT_TEST_RESPONSE_CACHE_MB,
+            RunMode::Rating => DEFAULT_RESPONSE_CACHE_MB,
+        })
+    }
+
+    pub fn warmup(&self, mode: RunMode) -> bool {
+        self.warmup.unwrap_or(mode == RunMode::Rating)
+    }
+}
+
+// the raw values of the settings
+#[derive(Default)]
+struct Source {
+    flags: HashMap<String, String>,
+    env: HashMap<String, String>,
+    file: HashMap<String, String>,
+    file_path: String,
+    // the values with their origins in the order of the lookups
+    used: RefCell<Vec<(&'static str, Option<String>, String)>>,
+}
+
+impl Source {
+    // the toml file has the same keys as the flags, the lists may be arrays
+    fn read_file(&mut self, path: PathBuf) -> Result<(), String> {
+        let s = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let table: toml::Table = s.parse().map_err(|e| format!("{}: {}", path.display(), e))?;
+
+        for (key, value) in table {
+            if !KEYS.contains(&key.as_str()) {
+                return Err(format!("{}: unknown setting {}", path.display(), key));
+            }
+
+            let value = match value {
+                toml::Value::Array(values) => values.iter().map(scalar).collect::<Option<Vec<_>>>().map(|v| v.join(",")),
+                value => scalar(&value),
+            };
+            let value = value.ok_or_else(|| format!("{}: invalid value of {}", path.display(), key))?;
+            self.file.insert(key, value);
+        }
+
+        self.file_path = path.display().to_string();
+
+        Ok(())
+    }
+
+    fn get(&self, key: &'static str) -> Option<(String, String)> {
+        let env_name = format!("HLCUP_{}", key.to_uppercase());
+
+        if let Some(value) = self.flags.get(key) {
+            Some((value.clone(), format!("--{}", key.replace('_', "-"))))
+        } else if let Some(value) = self.env.get(&env_name) {
+            Some((value.clone(), env_name))
+        } else {
+            self.file
+                .get(key)
+                .map(|value| (value.clone(), self.file_path.clone()))
+        }
+    }
+
+    fn var<T: FromStr>(&self, key: &'static str, default: &str) -> Result<T, String> {
+        let (value, origin) = self
+            .get(key)
+            .unwrap_or_else(|| (default.to_string(), "default".to_string()));
+
+        let parsed = value.parse().map_err(|_| match SECRET_KEYS.contains(&key) {
+            true => format!("invalid value of {} ({})", key, origin),
+            false => format!("invalid value of {} ({}): {}", key, origin, value),
+        })?;
+        self.used.borrow_mut().push((key, Some(value), origin));
+
+        Ok(parsed)
+    }
+
+    fn opt<T: FromStr>(&self, key: &'static str) -> Result<Option<T>, String> {
+        match self.get(key) {
+            Some(_) => self.var(key, "").map(Some),
+            None => {
+                self.used.borrow_mut().push((key, None, "default".to_string()));
+                Ok(None)
+            }
+        }
+    }
+}
+
+fn scalar(value: &toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(s) => Some(s.clone()),
+        toml::Value::Integer(i) => Some(i.to_string()),
+        toml::Value::Float(f) => Some(f.to_string()),
+        toml::Value::Boolean(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the tests don't read the environment of the process
+    fn load_with_env(args: &[&str], env: &[(&str, &str)]) -> Result<Config, String> {
+        let args = args.iter().map(|a| a.to_string()).collect();
+        let env = env.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        Config::load_with_env(args, env).map(|(config, _)| config)
+    }
+
+    fn load(args: &[&str]) -> Result<Config, String> {
+        load_with_env(args, &[])
+    }
+
+    #[test]
+    fn parses_the_strict_switch() {
+        assert!(!load(&[]).unwrap().strict_load);
+        assert!(load(&["--strict"]).unwrap().strict_load);
+        assert!(load(&["--strict=true"]).unwrap().strict_load);
+        assert!(!load(&["--strict=false"]).unwrap().strict_load);
+        assert!(load(&["--strict=yes"]).is_err());
+
+        // the switch doesn't take the next argument
+        let args = vec!["--strict".to_string(), "export".to_string()];
+        let (config, rest) = Config::load_with_env(args, HashMap::new()).unwrap();
+        assert!(config.strict_load);
+        assert_eq!(rest, vec!["export"]);
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        assert_eq!(load(&["--workers", "1"]).unwrap().workers, Some(1));
+        assert!(load(&["--workers", "0"]).is_err());
+        assert!(load(&["--snapshot-keep", "0"]).is_err());
+        assert!(load(&["--events-buffer", "1000000"]).is_ok());
+        assert!(load(&["--events-buffer", "1000001"]).is_err());
+        assert!(load(&["--wal-fsync", "0"]).is_err());
+        assert!(load(&["--bind", " , "]).is_err());
+    }
+
+    #[test]
+    fn layers_the_flags_over_the_env_over_the_file() {
+        let dir = std::env::temp_dir().join(format!("hlcup-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("hlcup.toml");
+        fs::write(&path, "backlog = 10\nmax_connections = 20\naccess_log_sample = 30\nbind = [\"a:1\", \"b:2\"]\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        let env = [("HLCUP_BACKLOG", "11"), ("HLCUP_MAX_CONNECTIONS", "21")];
+
+        let config = load_with_env(&["--config", path, "--backlog=12"], &env).unwrap();
+        assert_eq!(config.backlog, 12);
+        assert_eq!(config.max_connections, 21);
+        assert_eq!(config.access_log_sample, 30);
+        assert_eq!(config.bind, vec!["a:1", "b:2"]);
+        assert_eq!(config.json_limit_kb, DEFAULT_JSON_LIMIT_KB.parse::<usize>().unwrap());
+
+        let origin = |key| config.settings.iter().find(|(k, _, _)| *k == key).unwrap().2.clone();
+        assert_eq!(origin("backlog"), "--backlog");
+        assert_eq!(origin("max_connections"), "HLCUP_MAX_CONNECTIONS");
+        assert_eq!(origin("access_log_sample"), path);
+
+        // the file of HLCUP_CONFIG, the invalid values name their origin
+        let config = load_with_env(&[], &[("HLCUP_CONFIG", path)]).unwrap();
+        assert_eq!((config.backlog, config.max_connections), (10, 20));
+        let err = load_with_env(&["--config", path], &[("HLCUP_BACKLOG", "x")]).err().unwrap();
+        assert_eq!(err, "invalid value of backlog (HLCUP_BACKLOG): x");
+
+        fs::write(dir.join("hlcup.toml"), "backlogs = 1\n").unwrap();
+        assert!(load(&["--config", path]).err().unwrap().contains("unknown setting backlogs"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
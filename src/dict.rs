@@ -2,7 +2,7 @@ use std::collections::HashMap;
 
 // dictionary
 // for storing repeatable entities strings such as: countries, cities, firstnames etc.
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct Dict {
     pub map: HashMap<String, u32>,
     vec: Vec<String>,
@@ -16,29 +16,41 @@ impl Dict {
         }
     }
 
+    // restores the dictionary from its entries ordered by id
+    pub fn from_entries(vec: Vec<String>) -> Self {
+        let map = vec
+            .iter()
+            .enumerate()
+            .map(|(idx, s)| (s.clone(), idx as u32))
+            .collect();
+
+        Dict { map, vec }
+    }
+
+    // entries ordered by id
+    pub fn entries(&self) -> &[String] {
+        &self.vec
+    }
+
     // returns id of entry if entry exists
     // otherwise creates an entry and returns its id
     pub fn put(&mut self, key: String) -> u32 {
-        let val = self.map.get(&key);
-        if val.is_some() {
-            return val.unwrap().clone();
+        if let Some(val) = self.map.get(&key) {
+            return *val;
         }
 
         self.vec.push(key.clone());
         self.map.insert(key, self.vec.len() as u32 - 1);
 
-        return self.vec.len() as u32 - 1;
+        self.vec.len() as u32 - 1
     }
 
-    pub fn get_by_idx(&self, idx: usize) -> String {
-        self.vec[idx].to_string()
+    // borrows the entry, so responses can be serialized without copying strings
+    pub fn get_by_idx(&self, idx: usize) -> &str {
+        &self.vec[idx]
     }
 
     pub fn exist(&self, s: &str) -> bool {
-        let val = self.map.get(s);
-        if val.is_some() {
-            return true;
-        }
-        return false;
+        self.map.contains_key(s)
     }
 }
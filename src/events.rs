@@ -0,0 +1,204 @@
+use std::{collections::VecDeque, sync::Mutex};
+
+use bytes::Bytes;
+use tokio::sync::mpsc;
+
+use crate::mutation::Mutation;
+
+// number of the events a subscriber may lag behind before it's disconnected
+const SUBSCRIBER_CAPACITY: usize = 1024;
+
+// stream of the data changes for the server-sent events.
+// every committed mutation becomes an event with its sequence number as the id,
+// the recent events are kept in a ring buffer so a client can resume
+// from the id it has seen last
+pub struct EventBus {
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    last_id: u64,
+    capacity: usize,
+    // pre-formatted events with their ids, the oldest first
+    recent: VecDeque<(u64, Bytes)>,
+    subscribers: Vec<mpsc::Sender<Bytes>>,
+}
+
+impl EventBus {
+    // the ids are the sequence numbers of the mutations,
+    // last_id is the sequence number of the storage at the start
+    pub fn new(last_id: u64, capacity: usize) -> Self {
+        EventBus {
+            inner: Mutex::new(Inner {
+                last_id,
+                capacity,
+                recent: VecDeque::with_capacity(capacity),
+                subscribers: Vec::new(),
+            }),
+        }
+    }
+
+    pub fn publish(&self, id: u64, mutation: &Mutation) {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.last_id = id;
+
+        let data = match serde_json::to_string(mutation) {
+            Ok(data) => data,
+            Err(e) => {
+                error!("events: serialization error: {}", e);
+                return;
+            }
+        };
+        let event = Bytes::from(format!(
+            "id: {}\nevent: {}\ndata: {}\n\n",
+            id,
+            mutation.kind(),
+            data
+        ));
+
+        if inner.capacity > 0 {
+            if inner.recent.len() == inner.capacity {
+                inner.recent.pop_front();
+            }
+            inner.recent.push_back((id, event.clone()));
+        }
+
+        // the subscribers which are gone or lag too much are dropped,
+        // the clients reconnect with Last-Event-ID
+        inner
+            .subscribers
+            .retain(|tx| tx.try_send(event.clone()).is_ok());
+    }
+
+    // subscribes to the new events, returns the buffered events after last_id
+    // to send before them. if the events after last_id are not buffered anymore
+    // or last_id is unknown, a "reset" event goes first,
+    // so the client knows it has to resync
+    pub fn subscribe(&self, last_id: Option<u64>) -> (Vec<Bytes>, mpsc::Receiver<Bytes>) {
+        let mut inner = self.inner.lock().unwrap();
+
+        let mut backlog = Vec::new();
+
+        if let Some(last_id) = last_id {
+            let oldest = inner
+                .recent
+                .front()
+                .map(|(id, _)| *id)
+                .unwrap_or(inner.last_id.saturating_add(1));
+
+            // last_id comes from the client, so it may be any number
+            if last_id.saturating_add(1) < oldest || last_id > inner.last_id {
+                backlog.push(reset_event(inner.last_id));
+            }
+
+            backlog.extend(
+                inner
+                    .recent
+                    .iter()
+                    .filter(|(id, _)| *id > last_id)
+                    .map(|(_, event)| event.clone()),
+            );
+        }
+
+        let (tx, rx) = mpsc::channel(SUBSCRIBER_CAPACITY);
+        inner.subscribers.push(tx);
+
+        debug!("events: subscribed after {:?}, {} buffered events", last_id, backlog.len());
+
+        (backlog, rx)
+    }
+
+    // forgets the buffered events after the data is replaced,
+    // the subscribers get the "reset" event
+    pub fn reset(&self, last_id: u64) {
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.last_id = last_id;
+        inner.recent.clear();
+
+        let event = reset_event(last_id);
+        inner
+            .subscribers
+            .retain(|tx| tx.try_send(event.clone()).is_ok());
+    }
+
+    // ends the streams of the subscribers, e.g. on shutdown,
+    // so they don't hold the server until the deadline
+    pub fn close(&self) {
+        self.inner.lock().unwrap().subscribers.clear();
+    }
+}
+
+fn reset_event(last_id: u64) -> Bytes {
+    Bytes::from(format!("event: reset\ndata: {{\"last_id\":{}}}\n\n", last_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model;
+
+    fn mutation(id: u32) -> Mutation {
+        Mutation::NewLocation(model::LocationJSON {
+            id,
+            ..Default::default()
+        })
+    }
+
+    fn ids(events: &[Bytes]) -> Vec<String> {
+        events
+            .iter()
+            .map(|e| {
+                let e = std::str::from_utf8(e).unwrap();
+                match e.strip_prefix("id: ") {
+                    Some(rest) => rest.lines().next().unwrap().to_string(),
+                    None => e.lines().next().unwrap().to_string(),
+                }
+            })
+            .collect()
+    }
+
+    // the bus with the events 1..=5 and 3 of them buffered
+    fn bus() -> EventBus {
+        let bus = EventBus::new(0, 3);
+        for id in 1..=5 {
+            bus.publish(id, &mutation(id as u32));
+        }
+        bus
+    }
+
+    #[test]
+    fn resumes_after_the_last_id() {
+        let bus = bus();
+
+        assert!(bus.subscribe(None).0.is_empty());
+        assert_eq!(ids(&bus.subscribe(Some(3)).0), vec!["4", "5"]);
+        assert_eq!(ids(&bus.subscribe(Some(2)).0), vec!["3", "4", "5"]);
+        assert!(bus.subscribe(Some(5)).0.is_empty());
+
+        let (_, mut rx) = bus.subscribe(Some(5));
+        bus.publish(6, &mutation(6));
+        assert_eq!(ids(&[rx.try_recv().unwrap()]), vec!["6"]);
+    }
+
+    #[test]
+    fn resets_the_unknown_ids() {
+        let bus = bus();
+
+        // the event 2 isn't buffered anymore
+        assert_eq!(ids(&bus.subscribe(Some(1)).0), vec!["event: reset", "3", "4", "5"]);
+        assert_eq!(ids(&bus.subscribe(Some(9)).0), vec!["event: reset"]);
+        assert_eq!(ids(&bus.subscribe(Some(u64::MAX)).0), vec!["event: reset"]);
+
+        // the subscribers and the new clients of the replaced data
+        let (_, mut rx) = bus.subscribe(None);
+        bus.reset(7);
+        assert_eq!(ids(&[rx.try_recv().unwrap()]), vec!["event: reset"]);
+        assert_eq!(ids(&bus.subscribe(Some(5)).0), vec!["event: reset"]);
+        assert!(bus.subscribe(Some(7)).0.is_empty());
+
+        let bus = EventBus::new(u64::MAX, 3);
+        assert!(bus.subscribe(Some(u64::MAX)).0.is_empty());
+    }
+}
@@ -0,0 +1,181 @@
+use std::io::{Seek, Write};
+
+use serde::Serialize;
+use zip::{result::ZipResult, write::FileOptions, ZipWriter};
+
+use crate::{
+    options::{Options, RunMode},
+    storage::Storage,
+};
+
+// default number of entities per json file of the archive
+pub const DEFAULT_PER_FILE: usize = 10000;
+
+// writes the storage to the zip archive in the layout load.rs reads:
+// users_N.json, locations_N.json, visits_N.json and options.txt with the run mode
+pub fn write_zip<W: Write + Seek>(s: &dyn Storage, w: W, per_file: usize, mode: RunMode) -> ZipResult<W> {
+    let per_file = per_file.max(1);
+
+    let mut zip = ZipWriter::new(w);
+
+    let users = s.user_ids().filter_map(|id| s.user(id));
+    write_entities(&mut zip, "users", users, per_file)?;
+
+    let locations = s.location_ids().filter_map(|id| s.location(id));
+    write_entities(&mut zip, "locations", locations, per_file)?;
+
+    let visits = s.visit_ids().filter_map(|id| s.visit(id));
+    write_entities(&mut zip, "visits", visits, per_file)?;
+
+    zip.start_file("options.txt", FileOptions::default())?;
+    zip.write_all(options(s, mode).as_bytes())?;
+
+    zip.finish()
+}
+
+// contents of options.txt for the storage
+pub fn options(s: &dyn Storage, mode: RunMode) -> String {
+    Options {
+        timestamp: s.timestamp(),
+        mode,
+    }
+    .to_string()
+}
+
+// writes the entities to the files {name}_1.json, {name}_2.json...
+// each file is {"<name>": [...]} with at most per_file entities
+fn write_entities<W, T, I>(zip: &mut ZipWriter<W>, name: &str, entities: I, per_file: usize) -> ZipResult<()>
+where
+    W: Write + Seek,
+    T: Serialize,
+    I: Iterator<Item = T>,
+{
+    let mut file_num = 0;
+    let mut in_file = 0;
+
+    for entity in entities {
+        if in_file == per_file {
+            zip.write_all(b"]}")?;
+            in_file = 0;
+        }
+
+        if in_file == 0 {
+            file_num += 1;
+            zip.start_file(format!("{}_{}.json", name, file_num), FileOptions::default())?;
+            write!(zip, "{{\"{}\":[", name)?;
+        } else {
+            zip.write_all(b",")?;
+        }
+
+        serde_json::to_writer(&mut *zip, &entity).map_err(std::io::Error::from)?;
+        in_file += 1;
+    }
+
+    if file_num > 0 {
+        zip.write_all(b"]}")?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Read};
+
+    use zip::ZipArchive;
+
+    use super::*;
+    use crate::{load, model, storage::MemStorage};
+
+    fn storage() -> MemStorage {
+        let mut s = MemStorage::new();
+        s.set_timestamp(1_503_695_452);
+
+        for id in [1, 2, 3, 90_000_000] {
+            s.store_user(&model::UserJSON {
+                id,
+                email: format!("u{}@mail.ru", id),
+                first_name: "Анна".to_string(),
+                last_name: "O\"Neil, Jr".to_string(),
+                gender: if id == 2 { "m" } else { "f" }.to_string(),
+                birth_date: -(id as i32 % 1000) * 1_000_000,
+            })
+            .unwrap();
+        }
+        for id in [10, 20] {
+            s.store_location(&model::LocationJSON {
+                id,
+                distance: id,
+                city: "Москва".to_string(),
+                country: "Россия".to_string(),
+                place: format!("Место {}", id),
+            })
+            .unwrap();
+        }
+        for id in 1..=7 {
+            s.store_visit(&model::VisitJSON {
+                id,
+                user: [1, 2, 3, 90_000_000][id as usize % 4],
+                location: if id % 2 == 0 { 10 } else { 20 },
+                mark: (id % 5) as u8,
+                visited_at: 1_000 * (id as i32 % 3),
+            })
+            .unwrap();
+        }
+
+        s
+    }
+
+    fn json<T: Serialize>(v: T) -> String {
+        serde_json::to_string(&v).unwrap()
+    }
+
+    #[test]
+    fn loads_the_exported_archive() {
+        let s = storage();
+
+        let path = std::env::temp_dir().join(format!("hlcup-export-{}.zip", std::process::id()));
+        write_zip(&s, fs::File::create(&path).unwrap(), 3, RunMode::Rating).unwrap();
+
+        let mut archive = ZipArchive::new(fs::File::open(&path).unwrap()).unwrap();
+        let mut names: Vec<_> = archive.file_names().map(String::from).collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec!["locations_1.json", "options.txt", "users_1.json", "users_2.json", "visits_1.json", "visits_2.json", "visits_3.json"]
+        );
+        let mut text = String::new();
+        archive.by_name("options.txt").unwrap().read_to_string(&mut text).unwrap();
+        let options = Options::parse(&text).unwrap();
+        assert_eq!(options.mode, RunMode::Rating);
+
+        let mut loaded = MemStorage::new();
+        let report = load::run(&mut loaded, &path, &options, &load::Progress::default()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(report.is_clean());
+        assert_eq!((report.files, report.users, report.locations, report.visits), (6, 4, 2, 7));
+        assert_eq!(loaded.timestamp(), s.timestamp());
+
+        for id in s.user_ids() {
+            assert_eq!(json(loaded.user(id)), json(s.user(id)));
+
+            let params = model::UserVisitsParams {
+                from_date: None,
+                to_date: None,
+                country: None,
+                to_distance: None,
+            };
+            assert_eq!(
+                json(loaded.user_visits(id, &params).unwrap()),
+                json(s.user_visits(id, &params).unwrap())
+            );
+        }
+        for id in s.location_ids() {
+            assert_eq!(json(loaded.location(id)), json(s.location(id)));
+        }
+        for id in s.visit_ids() {
+            assert_eq!(json(loaded.visit(id)), json(s.visit(id)));
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use std::{
+    env,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom},
+    path::PathBuf,
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use actix_web::{get, http::header, post, web, HttpResponse};
+use bytes::Bytes;
+use futures_util::stream;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{auth::Caller, export, logging, reload, state::AppState};
+
+// size of the chunks the exported archive is streamed with
+const EXPORT_CHUNK: usize = 256 * 1024;
+
+// makes the names of the temporary export files unique
+static EXPORT_NUM: AtomicU64 = AtomicU64::new(0);
+
+#[post("/admin/snapshot", name = "admin_snapshot")]
+async fn snapshot(data: web::Data<AppState>) -> HttpResponse {
+    let res = web::block(move || data.snapshot()).await;
+
+    match res {
+        Ok(Ok(path)) => HttpResponse::Ok()
+            .insert_header(header::ContentType::json())
+            .body(json!({ "path": path }).to_string()),
+        Ok(Err(e)) if e.kind() == io::ErrorKind::Unsupported => HttpResponse::NotImplemented()
+            .insert_header(header::ContentType::json())
+            .body(json!({ "error": e.to_string() }).to_string()),
+        Ok(Err(e)) => HttpResponse::InternalServerError()
+            .insert_header(header::ContentType::json())
+            .body(json!({ "error": e.to_string() }).to_string()),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+// what the data load at the start has stored and rejected, see load::Report
+#[get("/admin/load-report", name = "admin_load_report")]
+async fn load_report(data: web::Data<AppState>) -> HttpResponse {
+    match &*data.load_report.lock().unwrap() {
+        Some(report) => HttpResponse::Ok().json(report),
+        None => HttpResponse::NotFound()
+            .insert_header(header::ContentType::json())
+            .body(json!({ "error": "the data wasn't loaded from the files" }).to_string()),
+    }
+}
+
+// options.txt of the loaded data, see options.rs
+#[get("/admin/options", name = "admin_options")]
+async fn options(data: web::Data<AppState>) -> HttpResponse {
+    match &*data.options.lock().unwrap() {
+        Some(options) => HttpResponse::Ok().json(options),
+        None => HttpResponse::NotFound()
+            .insert_header(header::ContentType::json())
+            .body(json!({ "error": "the server has no options of the data" }).to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ReloadParams {
+    // the configured data files by default
+    data: Option<PathBuf>,
+    options: Option<PathBuf>,
+    #[serde(default)]
+    replay: bool,
+}
+
+// starts loading the new data in the background, see reload.rs.
+// the reload replaces the data, so it's in the audit log
+#[post("/admin/reload", name = "admin_reload")]
+async fn reload_data(
+    data: web::Data<AppState>,
+    params: web::Query<ReloadParams>,
+    caller: Option<web::ReqData<Caller>>,
+) -> HttpResponse {
+    let params = params.into_inner();
+    let path = params.data.unwrap_or_else(|| data.config.data_path.clone());
+    let options_path = params
+        .options
+        .or_else(|| data.config.options_path.clone().filter(|_| path == data.config.data_path));
+
+    let source = path.display().to_string();
+
+    match reload::start(data, path, options_path, params.replay) {
+        Ok(()) => {
+            if let Some(caller) = caller {
+                logging::audit(&[
+                    ("key", json!(caller.name)),
+                    ("role", json!(caller.role.name())),
+                    ("mutation", json!("reload")),
+                    ("data", json!(source)),
+                ]);
+            }
+
+            HttpResponse::Accepted()
+                .insert_header(header::ContentType::json())
+                .body(json!({ "state": "running" }).to_string())
+        }
+        Err(e) => {
+            let mut resp = match e {
+                reload::StartError::Unsupported => HttpResponse::NotImplemented(),
+                reload::StartError::ReadOnly => HttpResponse::Forbidden(),
+                reload::StartError::Running => HttpResponse::Conflict(),
+                reload::StartError::Options(_) => HttpResponse::BadRequest(),
+                reload::StartError::NotDurable => HttpResponse::Conflict(),
+                reload::StartError::PathNotAllowed(_) => HttpResponse::Forbidden(),
+            };
+            resp.insert_header(header::ContentType::json())
+                .body(json!({ "error": e.to_string() }).to_string())
+        }
+    }
+}
+
+#[get("/admin/reload", name = "admin_reload")]
+async fn reload_status(data: web::Data<AppState>) -> HttpResponse {
+    let status = data.reload.lock().unwrap().clone();
+    HttpResponse::Ok().json(status)
+}
+
+#[derive(Deserialize)]
+pub struct ExportParams {
+    per_file: Option<usize>,
+}
+
+// streams the zip archive with the whole dataset in the layout load.rs reads
+#[get("/admin/export", name = "admin_export")]
+async fn export_data(data: web::Data<AppState>, params: web::Query<ExportParams>) -> HttpResponse {
+    let per_file = params.per_file.unwrap_or(export::DEFAULT_PER_FILE);
+    let mode = data.run_mode();
+
+    // the archive is written to a temporary file while the storage is read-locked,
+    // and then streamed without holding the lock
+    let res = web::block(move || -> io::Result<File> {
+        let path = env::temp_dir().join(format!(
+            "hlcup-export-{}-{}.zip",
+            process::id(),
+            EXPORT_NUM.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        // the file is deleted once the response is sent and the file is closed
+        fs::remove_file(&path)?;
+
+        let s = data.read_storage();
+        let mut file = export::write_zip(&**s, file, per_file, mode)?;
+        file.seek(SeekFrom::Start(0))?;
+
+        Ok(file)
+    })
+    .await;
+
+    let file = match res {
+        Ok(Ok(file)) => file,
+        Ok(Err(e)) => {
+            return HttpResponse::InternalServerError()
+                .insert_header(header::ContentType::json())
+                .body(json!({ "error": e.to_string() }).to_string())
+        }
+        Err(_) => return HttpResponse::InternalServerError().finish(),
+    };
+
+    let body = stream::unfold(Some(file), |file| async move {
+        let mut file = file?;
+
+        let res = web::block(move || {
+            let mut buf = vec![0; EXPORT_CHUNK];
+            let n = file.read(&mut buf)?;
+            buf.truncate(n);
+            Ok::<_, io::Error>((buf, file))
+        })
+        .await;
+
+        match res {
+            Ok(Ok((buf, _))) if buf.is_empty() => None,
+            Ok(Ok((buf, file))) => Some((Ok(Bytes::from(buf)), Some(file))),
+            Ok(Err(e)) => Some((Err(actix_web::Error::from(e)), None)),
+            Err(e) => Some((Err(actix_web::Error::from(e)), None)),
+        }
+    });
+
+    HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, "application/zip"))
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"data.zip\"",
+        ))
+        .streaming(body)
+}
@@ -1,88 +1,51 @@
 use actix_web::{http::header, post, web, HttpResponse};
 
-use crate::{model, AppState};
+use crate::{auth::Caller, model, mutation::Mutation, state::AppState, storage::StoreError};
 
-#[post("/users/new")]
-async fn new_user(data: web::Data<AppState>, user: web::Json<model::UserJSON>) -> HttpResponse {
-    let mut s = data.storage.write().unwrap();
-
-    if s.users.len() > user.id as usize {
-        // user with this id already exists
-        return HttpResponse::BadRequest()
-            .insert_header(header::ContentType::json())
-            .body("{}");
-    }
-
-    let res = s.store_user(
-        user.id as usize,
-        &user.email,
-        &user.first_name,
-        &user.last_name,
-        user.birth_date,
-        user.gender.as_str(),
-    );
-
-    match res {
-        Ok(_) => {
-            HttpResponse::Ok()
-                .insert_header(header::ContentType::json())
-                .body("{}")
-        }
-        Err(_) => {
-            HttpResponse::BadRequest()
-                .insert_header(header::ContentType::json())
-                .body("{}")
-        }
-    }
+#[post("/users/new", name = "new_user")]
+async fn new_user(
+    data: web::Data<AppState>,
+    user: web::Json<model::UserJSON>,
+    caller: Option<web::ReqData<Caller>>,
+) -> HttpResponse {
+    // fails if user with this id already exists
+    commit_response(data.commit(&Mutation::NewUser(user.into_inner()), caller.as_deref()))
 }
 
-#[post("/locations/new")]
+#[post("/locations/new", name = "new_location")]
 async fn new_location(
     data: web::Data<AppState>,
     location: web::Json<model::LocationJSON>,
+    caller: Option<web::ReqData<Caller>>,
 ) -> HttpResponse {
-    let mut s = data.storage.write().unwrap();
-
-    if s.locations.len() > location.id as usize {
-        // location is already exist
-        return HttpResponse::BadRequest()
-            .insert_header(header::ContentType::json())
-            .body("{}");
-    }
-
-    s.store_location(
-        location.id as usize,
-        &location.country,
-        &location.city,
-        &location.place,
-        location.distance,
-    );
-
-    HttpResponse::Ok()
-        .insert_header(header::ContentType::json())
-        .body("{}")
+    // fails if location with this id already exists
+    commit_response(data.commit(&Mutation::NewLocation(location.into_inner()), caller.as_deref()))
 }
 
-#[post("/visits/new")]
-async fn new_visit(data: web::Data<AppState>, visit: web::Json<model::VisitJSON>) -> HttpResponse {
-    let mut s = data.storage.write().unwrap();
+#[post("/visits/new", name = "new_visit")]
+async fn new_visit(
+    data: web::Data<AppState>,
+    visit: web::Json<model::VisitJSON>,
+    caller: Option<web::ReqData<Caller>>,
+) -> HttpResponse {
+    // fails if visit with this id already exists
+    // or the visit references unknown user or location
+    commit_response(data.commit(&Mutation::NewVisit(visit.into_inner()), caller.as_deref()))
+}
 
-    if s.visits.len() > visit.id as usize {
-        // visit is already exist
-        return HttpResponse::BadRequest()
+fn commit_response(res: Result<(), StoreError>) -> HttpResponse {
+    match res {
+        Ok(_) => HttpResponse::Ok()
             .insert_header(header::ContentType::json())
-            .body("{}");
+            .body("{}"),
+        Err(StoreError::ReadOnly) => HttpResponse::Forbidden()
+            .insert_header(header::ContentType::json())
+            .body("{}"),
+        Err(StoreError::Io) => HttpResponse::InternalServerError()
+            .insert_header(header::ContentType::json())
+            .body("{}"),
+        Err(_) => HttpResponse::BadRequest()
+            .insert_header(header::ContentType::json())
+            .body("{}"),
     }
-
-    s.store_visit(
-        visit.id,
-        visit.user,
-        visit.location,
-        visit.visited_at,
-        visit.mark,
-    );
-
-    HttpResponse::Ok()
-        .insert_header(header::ContentType::json())
-        .body("{}")
 }
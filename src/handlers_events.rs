@@ -0,0 +1,31 @@
+use actix_web::{get, http::header, web, HttpRequest, HttpResponse};
+use futures_util::{stream, StreamExt};
+
+use crate::state::AppState;
+
+// server-sent events stream of the committed mutations,
+// a reconnecting client gets the missed events after its Last-Event-ID
+#[get("/events", name = "events")]
+async fn events(data: web::Data<AppState>, req: HttpRequest) -> HttpResponse {
+    let last_id = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok());
+
+    let (backlog, rx) = data.events.subscribe(last_id);
+
+    let live = stream::unfold(rx, |mut rx| async move {
+        let event = rx.recv().await?;
+        Some((event, rx))
+    });
+
+    let body = stream::iter(backlog)
+        .chain(live)
+        .map(Ok::<_, actix_web::Error>);
+
+    HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, "text/event-stream"))
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .streaming(body)
+}
@@ -0,0 +1,39 @@
+use actix_web::{get, http::header, web, HttpResponse};
+use serde_json::json;
+
+use crate::state::AppState;
+
+// liveness, the server is up even if the data is loading
+#[get("/health", name = "health")]
+async fn health() -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header(header::ContentType::json())
+        .body(json!({ "status": "ok" }).to_string())
+}
+
+// readiness, 503 with the progress until the data is loaded
+#[get("/ready", name = "ready")]
+async fn ready(data: web::Data<AppState>) -> HttpResponse {
+    if data.is_ready() {
+        return HttpResponse::Ok()
+            .insert_header(header::ContentType::json())
+            .body(json!({ "ready": true }).to_string());
+    }
+
+    loading(&data)
+}
+
+// the response to the requests before the data is loaded
+pub fn loading(data: &AppState) -> HttpResponse {
+    HttpResponse::ServiceUnavailable()
+        .insert_header(header::ContentType::json())
+        .insert_header((header::RETRY_AFTER, "1"))
+        .body(
+            json!({
+                "ready": false,
+                "phase": data.progress.phase(),
+                "progress": data.progress.percent(),
+            })
+            .to_string(),
+        )
+}
@@ -0,0 +1,217 @@
+use std::collections::BTreeMap;
+
+use actix_web::{http::header, post, web, HttpResponse};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    auth::Caller,
+    import::{Format, Lines},
+    load::{Kind, Rejected},
+    mutation::Mutation,
+    state::AppState,
+    storage::StoreError,
+};
+
+// number of the invalid lines kept in the summary
+const SUMMARY_LINES: usize = 10;
+
+// number of the entities committed by one blocking task
+const COMMIT_BATCH: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct ImportParams {
+    entity: String,
+    format: String,
+}
+
+#[derive(Serialize, Default)]
+struct Summary {
+    imported: usize,
+    rejected: BTreeMap<StoreError, Rejected>,
+    invalid: Invalid,
+}
+
+// the lines which aren't entities
+#[derive(Serialize, Default)]
+struct Invalid {
+    count: usize,
+    lines: Vec<InvalidLine>,
+}
+
+#[derive(Serialize)]
+struct InvalidLine {
+    line: usize,
+    error: String,
+}
+
+impl Summary {
+    fn invalid(&mut self, line: usize, error: String) {
+        self.invalid.count += 1;
+        if self.invalid.lines.len() < SUMMARY_LINES {
+            self.invalid.lines.push(InvalidLine { line, error });
+        }
+    }
+}
+
+// imports the ndjson or csv entities of the body, e.g.
+// POST /import?entity=visits&format=csv. the body is read as it comes,
+// every entity is committed the same way as with POST /<entity>/new,
+// so it's validated, written to the log and published as an event.
+// the lines are parsed as they come and committed in batches on the
+// blocking thread pool, as the commits lock the storage and may fsync the log.
+// the response is the summary of the imported and rejected entities
+#[post("/import", name = "import")]
+async fn import(
+    data: web::Data<AppState>,
+    params: web::Query<ImportParams>,
+    caller: Option<web::ReqData<Caller>>,
+    mut body: web::Payload,
+) -> HttpResponse {
+    let kind = params.entity.parse::<Kind>();
+    let format = params.format.parse::<Format>().ok().filter(|f| *f != Format::Json);
+
+    let (kind, format) = match (kind, format) {
+        (Ok(kind), Some(format)) => (kind, format),
+        _ => {
+            return HttpResponse::BadRequest()
+                .insert_header(header::ContentType::json())
+                .body(json!({ "error": "entity must be users, locations or visits, format ndjson or csv" }).to_string())
+        }
+    };
+
+    if data.config.leader.is_some() {
+        return HttpResponse::Forbidden()
+            .insert_header(header::ContentType::json())
+            .body(json!({ "error": StoreError::ReadOnly.to_string() }).to_string());
+    }
+
+    let mut importer = Importer {
+        lines: Lines::new(kind, format),
+        kind,
+        caller: caller.map(web::ReqData::into_inner),
+        line_num: 0,
+        batch: Vec::new(),
+        summary: Summary::default(),
+    };
+
+    // the incomplete last line of the chunks read so far
+    let mut buf = Vec::new();
+    let mut size = 0;
+    let limit = data.config.import_limit_mb * 1024 * 1024;
+
+    while let Some(chunk) = body.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                return HttpResponse::BadRequest()
+                    .insert_header(header::ContentType::json())
+                    .body(json!({ "error": e.to_string(), "summary": importer.summary }).to_string())
+            }
+        };
+
+        // the entities before the limit stay imported
+        size += chunk.len();
+        if limit > 0 && size > limit {
+            return HttpResponse::PayloadTooLarge()
+                .insert_header(header::ContentType::json())
+                .body(json!({ "error": "the body is too large", "summary": importer.summary }).to_string());
+        }
+
+        buf.extend_from_slice(&chunk);
+
+        let mut start = 0;
+        while let Some(len) = buf[start..].iter().position(|b| *b == b'\n') {
+            importer.line(&buf[start..start + len]);
+            start += len + 1;
+        }
+        buf.drain(..start);
+
+        if importer.batch.len() >= COMMIT_BATCH {
+            if let Err(resp) = importer.commit(&data).await {
+                return resp;
+            }
+        }
+    }
+
+    // the last line without the line break
+    if !buf.is_empty() {
+        importer.line(&buf);
+    }
+
+    if let Err(resp) = importer.commit(&data).await {
+        return resp;
+    }
+
+    HttpResponse::Ok().json(importer.summary)
+}
+
+struct Importer {
+    lines: Lines,
+    kind: Kind,
+    caller: Option<Caller>,
+    line_num: usize,
+    // the parsed entities not committed yet
+    batch: Vec<Mutation>,
+    summary: Summary,
+}
+
+impl Importer {
+    fn line(&mut self, line: &[u8]) {
+        self.line_num += 1;
+
+        let res = std::str::from_utf8(line)
+            .map_err(|e| e.to_string())
+            .and_then(|line| parse(&mut self.lines, self.kind, line));
+
+        match res {
+            Ok(Some(mutation)) => self.batch.push(mutation),
+            Ok(None) => (),
+            Err(e) => self.summary.invalid(self.line_num, e),
+        }
+    }
+
+    // commits the parsed entities, the response with the summary
+    // of the entities committed so far if the blocking task failed
+    async fn commit(&mut self, data: &web::Data<AppState>) -> Result<(), HttpResponse> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+
+        let batch = std::mem::take(&mut self.batch);
+        let data = data.clone();
+        let caller = self.caller.clone();
+
+        let res = web::block(move || {
+            batch
+                .iter()
+                .map(|mutation| (mutation.id(), data.commit(mutation, caller.as_ref())))
+                .collect::<Vec<_>>()
+        })
+        .await;
+
+        let results = res.map_err(|e| {
+            HttpResponse::InternalServerError()
+                .insert_header(header::ContentType::json())
+                .body(json!({ "error": e.to_string(), "summary": self.summary }).to_string())
+        })?;
+
+        for (id, res) in results {
+            match res {
+                Ok(()) => self.summary.imported += 1,
+                Err(e) => self.summary.rejected.entry(e).or_default().add(id),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn parse(lines: &mut Lines, kind: Kind, line: &str) -> Result<Option<Mutation>, String> {
+    Ok(match kind {
+        Kind::Users => lines.parse(line)?.map(Mutation::NewUser),
+        Kind::Locations => lines.parse(line)?.map(Mutation::NewLocation),
+        Kind::Visits => lines.parse(line)?.map(Mutation::NewVisit),
+    })
+}
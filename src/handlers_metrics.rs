@@ -0,0 +1,11 @@
+use actix_web::{get, http::header, web, HttpResponse};
+
+use crate::state::AppState;
+
+// the metrics of the server in the prometheus text format, see metrics.rs
+#[get("/metrics", name = "metrics")]
+async fn metrics(data: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, "text/plain; version=0.0.4"))
+        .body(data.metrics.render(&data))
+}
@@ -0,0 +1,29 @@
+use std::io;
+
+use actix_web::{get, http::header, web, HttpResponse};
+
+use crate::state::AppState;
+
+// binary snapshot of the storage the followers bootstrap from,
+// it has the sequence number to follow the events from
+#[get("/replication/snapshot", name = "replication_snapshot")]
+async fn snapshot(data: web::Data<AppState>) -> HttpResponse {
+    let res = web::block(move || {
+        let mut buf = Vec::new();
+        data.read_storage().write_snapshot(&mut buf)?;
+        Ok::<_, io::Error>(buf)
+    })
+    .await;
+
+    match res {
+        Ok(Ok(buf)) => HttpResponse::Ok()
+            .insert_header((header::CONTENT_TYPE, "application/octet-stream"))
+            .body(buf),
+        Ok(Err(e)) if e.kind() == io::ErrorKind::Unsupported => HttpResponse::NotImplemented()
+            .insert_header(header::ContentType::json())
+            .body("{}"),
+        _ => HttpResponse::InternalServerError()
+            .insert_header(header::ContentType::json())
+            .body("{}"),
+    }
+}
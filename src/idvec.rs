@@ -0,0 +1,303 @@
+use std::{collections::HashMap, mem, ops::Index};
+
+// ids are split into pages of PAGE_SIZE entities,
+// pages are allocated only when the first entity of the page is stored
+const PAGE_BITS: u32 = 10;
+const PAGE_SIZE: usize = 1 << PAGE_BITS;
+
+// ids up to MAX_PAGES * PAGE_SIZE (64M) are stored in pages with O(1) lookup,
+// larger ids are stored in the hash map, so an occasional huge id
+// doesn't allocate the page table for the whole u32 range
+const MAX_PAGES: usize = 1 << 16;
+
+// vector of entities indexed by entity id,
+// supports arbitrary u32 ids with gaps
+pub struct IdVec<T> {
+    pages: Vec<Option<Box<[Option<T>]>>>,
+    sparse: HashMap<u32, T>,
+    len: usize,
+    // number of the allocated pages, they aren't freed
+    allocated: usize,
+}
+
+impl<T> Default for IdVec<T> {
+    fn default() -> Self {
+        IdVec {
+            pages: Vec::new(),
+            sparse: HashMap::new(),
+            len: 0,
+            allocated: 0,
+        }
+    }
+}
+
+impl<T> IdVec<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // number of stored entities
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // preallocates the page table for ids below count
+    pub fn reserve(&mut self, count: usize) {
+        let pages = count.div_ceil(PAGE_SIZE).min(MAX_PAGES);
+        self.pages.reserve(pages.saturating_sub(self.pages.len()));
+    }
+
+    pub fn get(&self, id: u32) -> Option<&T> {
+        let (page, slot) = split(id);
+        if page >= MAX_PAGES {
+            return self.sparse.get(&id);
+        }
+
+        match self.pages.get(page) {
+            Some(Some(page)) => page[slot].as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, id: u32) -> Option<&mut T> {
+        let (page, slot) = split(id);
+        if page >= MAX_PAGES {
+            return self.sparse.get_mut(&id);
+        }
+
+        match self.pages.get_mut(page) {
+            Some(Some(page)) => page[slot].as_mut(),
+            _ => None,
+        }
+    }
+
+    pub fn contains(&self, id: u32) -> bool {
+        self.get(id).is_some()
+    }
+
+    // stores the entity, returns the previous entity with this id
+    pub fn insert(&mut self, id: u32, val: T) -> Option<T> {
+        let (page, slot) = split(id);
+
+        let old = if page >= MAX_PAGES {
+            self.sparse.insert(id, val)
+        } else {
+            if self.pages.len() <= page {
+                self.pages.resize_with(page + 1, || None);
+            }
+
+            let allocated = &mut self.allocated;
+            let page = self.pages[page].get_or_insert_with(|| {
+                *allocated += 1;
+                (0..PAGE_SIZE).map(|_| None).collect()
+            });
+            page[slot].replace(val)
+        };
+
+        if old.is_none() {
+            self.len += 1;
+        }
+
+        old
+    }
+
+    pub fn remove(&mut self, id: u32) -> Option<T> {
+        let (page, slot) = split(id);
+
+        let old = if page >= MAX_PAGES {
+            self.sparse.remove(&id)
+        } else {
+            match self.pages.get_mut(page) {
+                Some(Some(page)) => page[slot].take(),
+                _ => None,
+            }
+        };
+
+        if old.is_some() {
+            self.len -= 1;
+        }
+
+        old
+    }
+
+    // approximate memory allocated by the vector itself,
+    // without the memory owned by the entities
+    pub fn heap_size(&self) -> usize {
+        self.pages.capacity() * mem::size_of::<Option<Box<[Option<T>]>>>()
+            + self.allocated * PAGE_SIZE * mem::size_of::<Option<T>>()
+            + self.sparse.capacity() * (mem::size_of::<(u32, T)>() + 1)
+    }
+
+    // approximate memory the insert of the id would allocate:
+    // a new page with its part of the page table or an entry of the hash map
+    pub fn insert_size(&self, id: u32) -> usize {
+        let (page, _) = split(id);
+        if page >= MAX_PAGES {
+            return match self.sparse.contains_key(&id) {
+                true => 0,
+                false => mem::size_of::<(u32, T)>() + 1,
+            };
+        }
+
+        match self.pages.get(page) {
+            Some(Some(_)) => 0,
+            _ => {
+                (page + 1).saturating_sub(self.pages.len()) * mem::size_of::<Option<Box<[Option<T>]>>>()
+                    + PAGE_SIZE * mem::size_of::<Option<T>>()
+            }
+        }
+    }
+
+    // iterates over the entities in ascending order of ids
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &T)> + '_ {
+        let dense = self
+            .pages
+            .iter()
+            .enumerate()
+            .filter_map(|(page_idx, page)| page.as_ref().map(|page| (page_idx, page)))
+            .flat_map(|(page_idx, page)| {
+                page.iter().enumerate().filter_map(move |(slot, val)| {
+                    val.as_ref()
+                        .map(|val| (((page_idx << PAGE_BITS) + slot) as u32, val))
+                })
+            });
+
+        let mut sparse: Vec<(u32, &T)> = self.sparse.iter().map(|(id, val)| (*id, val)).collect();
+        sparse.sort_unstable_by_key(|(id, _)| *id);
+
+        dense.chain(sparse)
+    }
+
+    // mutable access to all the entities, in no particular order
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut T> + '_ {
+        self.pages
+            .iter_mut()
+            .flatten()
+            .flat_map(|page| page.iter_mut().flatten())
+            .chain(self.sparse.values_mut())
+    }
+}
+
+impl<T> Index<u32> for IdVec<T> {
+    type Output = T;
+
+    // panics if there is no entity with the id,
+    // for lookups of ids referenced by the stored entities
+    fn index(&self, id: u32) -> &T {
+        match self.get(id) {
+            Some(val) => val,
+            None => panic!("no entity with id {}", id),
+        }
+    }
+}
+
+fn split(id: u32) -> (usize, usize) {
+    let id = id as usize;
+    (id >> PAGE_BITS, id & (PAGE_SIZE - 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the last id stored in the pages
+    const DENSE_MAX: u32 = (MAX_PAGES * PAGE_SIZE - 1) as u32;
+
+    #[test]
+    fn stores_sparse_ids() {
+        let mut v = IdVec::new();
+        assert!(v.is_empty());
+
+        for id in [5, 0, PAGE_SIZE as u32 * 3 + 1, DENSE_MAX, DENSE_MAX + 1, u32::MAX] {
+            assert_eq!(v.insert(id, id.wrapping_mul(2)), None);
+        }
+        assert_eq!(v.len(), 6);
+
+        // only the touched pages are allocated
+        assert_eq!(v.pages.iter().filter(|p| p.is_some()).count(), 3);
+        assert_eq!(v.sparse.len(), 2);
+
+        assert_eq!(v.get(5), Some(&10));
+        assert_eq!(v[DENSE_MAX], DENSE_MAX.wrapping_mul(2));
+        assert_eq!(v.get(u32::MAX), Some(&u32::MAX.wrapping_mul(2)));
+        assert_eq!(v.get(6), None);
+        assert_eq!(v.get(PAGE_SIZE as u32 * 2), None);
+        assert_eq!(v.get(DENSE_MAX + 2), None);
+
+        *v.get_mut(DENSE_MAX + 1).unwrap() = 1;
+        assert_eq!(v[DENSE_MAX + 1], 1);
+    }
+
+    #[test]
+    fn replaces_and_removes() {
+        let mut v = IdVec::new();
+
+        assert_eq!(v.insert(7, "a"), None);
+        assert_eq!(v.insert(7, "b"), Some("a"));
+        assert_eq!(v.insert(u32::MAX, "c"), None);
+        assert_eq!(v.insert(u32::MAX, "d"), Some("c"));
+        assert_eq!(v.len(), 2);
+
+        assert_eq!(v.remove(7), Some("b"));
+        assert_eq!(v.remove(7), None);
+        assert_eq!(v.remove(u32::MAX), Some("d"));
+        assert_eq!(v.remove(1 << 30), None);
+        assert!(v.is_empty());
+        assert!(!v.contains(7));
+    }
+
+    #[test]
+    fn iterates_in_ascending_order() {
+        let mut v = IdVec::new();
+        v.reserve(10 * PAGE_SIZE);
+
+        let ids = [u32::MAX, DENSE_MAX + 5, 3, DENSE_MAX + 1, 2000, 1, DENSE_MAX];
+        for id in ids {
+            v.insert(id, ());
+        }
+
+        let mut sorted = ids.to_vec();
+        sorted.sort();
+        assert_eq!(v.iter().map(|(id, _)| id).collect::<Vec<_>>(), sorted);
+        assert_eq!(v.values_mut().count(), ids.len());
+    }
+
+    #[test]
+    fn reports_the_allocated_pages() {
+        let mut v = IdVec::new();
+        assert_eq!(v.heap_size(), 0);
+
+        let page = PAGE_SIZE * mem::size_of::<Option<u64>>();
+        assert!(v.insert_size(5) >= page);
+        v.insert(5, 1u64);
+        assert!(v.heap_size() >= page);
+        assert_eq!(v.insert_size(6), 0);
+
+        // a page per id with the stride of the page size
+        for i in 1..10 {
+            v.insert(i * PAGE_SIZE as u32, 1);
+        }
+        assert!(v.heap_size() >= 10 * page);
+
+        let size = v.heap_size();
+        v.remove(5);
+        assert_eq!(v.heap_size(), size);
+
+        assert!(v.insert_size(u32::MAX) > 0);
+        v.insert(u32::MAX, 1);
+        assert_eq!(v.insert_size(u32::MAX), 0);
+        assert!(v.heap_size() > size);
+    }
+
+    #[test]
+    #[should_panic(expected = "no entity with id 4")]
+    fn panics_on_index_of_a_missing_id() {
+        let mut v = IdVec::new();
+        v.insert(3, 30);
+        let _ = v[4];
+    }
+}
@@ -0,0 +1,222 @@
+use std::str::FromStr;
+
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+
+use crate::load::Kind;
+
+// formats of the entity files, json is the {"<kind>": [...]} file of the contest
+// parsed by load.rs, the others have an entity per line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Format {
+    Json,
+    // a json object per line
+    Ndjson,
+    // the header line with the field names and a line per entity,
+    // the quoted values can't have line breaks
+    Csv,
+}
+
+impl Format {
+    // the format of the file by its extension
+    pub fn of_extension(ext: &str) -> Option<Self> {
+        match ext {
+            "json" => Some(Format::Json),
+            "ndjson" | "jsonl" => Some(Format::Ndjson),
+            "csv" => Some(Format::Csv),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Format::of_extension(s).ok_or(())
+    }
+}
+
+// parses the entities of the ndjson or csv lines one by one
+pub struct Lines {
+    kind: Kind,
+    format: Format,
+    // field names of the csv columns
+    header: Option<Vec<String>>,
+}
+
+impl Lines {
+    pub fn new(kind: Kind, format: Format) -> Self {
+        Lines {
+            kind,
+            format,
+            header: None,
+        }
+    }
+
+    // returns None for the lines without an entity: the empty ones and the csv header
+    pub fn parse<T: DeserializeOwned>(&mut self, line: &str) -> Result<Option<T>, String> {
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() {
+            return Ok(None);
+        }
+
+        match self.format {
+            Format::Json | Format::Ndjson => serde_json::from_str(line).map(Some).map_err(|e| e.to_string()),
+            Format::Csv => {
+                let values = split_csv(line)?;
+
+                let header = match &self.header {
+                    Some(header) => header,
+                    None => {
+                        let names = values
+                            .iter()
+                            .map(|name| name.trim_start_matches('\u{feff}').trim().to_string())
+                            .collect();
+                        self.header = Some(names);
+                        return Ok(None);
+                    }
+                };
+
+                if values.len() != header.len() {
+                    return Err(format!("{} values, {} expected", values.len(), header.len()));
+                }
+
+                let mut object = Map::new();
+                for (name, value) in header.iter().zip(values) {
+                    let value = if is_number(self.kind, name) {
+                        Value::Number(
+                            value
+                                .trim()
+                                .parse::<i64>()
+                                .map_err(|_| format!("invalid number {:?} of {}", value, name))?
+                                .into(),
+                        )
+                    } else {
+                        Value::String(value)
+                    };
+                    object.insert(name.clone(), value);
+                }
+
+                serde_json::from_value(Value::Object(object))
+                    .map(Some)
+                    .map_err(|e| e.to_string())
+            }
+        }
+    }
+}
+
+// parses the lines of the file calling f for every entity,
+// the errors have the line numbers
+pub fn for_each<T, F>(data: &[u8], kind: Kind, format: Format, mut f: F) -> Result<(), String>
+where
+    T: DeserializeOwned,
+    F: FnMut(T),
+{
+    let data = std::str::from_utf8(data).map_err(|e| e.to_string())?;
+
+    let mut lines = Lines::new(kind, format);
+
+    for (i, line) in data.lines().enumerate() {
+        if let Some(entity) = lines.parse(line).map_err(|e| format!("line {}: {}", i + 1, e))? {
+            f(entity);
+        }
+    }
+
+    Ok(())
+}
+
+// the csv values are strings, the fields of the numbers are converted
+fn is_number(kind: Kind, field: &str) -> bool {
+    match kind {
+        Kind::Users => matches!(field, "id" | "birth_date"),
+        Kind::Locations => matches!(field, "id" | "distance"),
+        Kind::Visits => matches!(field, "id" | "user" | "location" | "visited_at" | "mark"),
+    }
+}
+
+// splits the csv line by the commas, the values may be quoted with "
+// and have "" for the quote inside
+fn split_csv(line: &str) -> Result<Vec<String>, String> {
+    let mut values = Vec::new();
+    let mut value = String::new();
+
+    let mut chars = line.chars().peekable();
+    let mut quoted = false;
+
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', false) if value.is_empty() => quoted = true,
+            ('"', true) if chars.peek() == Some(&'"') => {
+                chars.next();
+                value.push('"');
+            }
+            ('"', true) => {
+                quoted = false;
+                match chars.peek() {
+                    None | Some(',') => (),
+                    Some(_) => return Err("unexpected character after the quoted value".to_string()),
+                }
+            }
+            (',', false) => values.push(std::mem::take(&mut value)),
+            (c, _) => value.push(c),
+        }
+    }
+
+    if quoted {
+        return Err("unterminated quoted value".to_string());
+    }
+    values.push(value);
+
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model;
+
+    #[test]
+    fn splits_csv_lines() {
+        assert_eq!(split_csv("1,a,,b").unwrap(), vec!["1", "a", "", "b"]);
+        assert_eq!(split_csv("\"a,b\",c").unwrap(), vec!["a,b", "c"]);
+        assert_eq!(split_csv("\"say \"\"hi\"\"\",x").unwrap(), vec!["say \"hi\"", "x"]);
+        assert_eq!(split_csv("\"\",\"\"\"\"").unwrap(), vec!["", "\""]);
+        // a quote inside an unquoted value is kept
+        assert_eq!(split_csv("a\"b,c").unwrap(), vec!["a\"b", "c"]);
+        assert_eq!(split_csv("").unwrap(), vec![""]);
+
+        assert!(split_csv("\"a\"b,c").is_err());
+        assert!(split_csv("\"a,b").is_err());
+    }
+
+    #[test]
+    fn parses_the_lines_by_the_header() {
+        let mut lines = Lines::new(Kind::Visits, Format::Csv);
+
+        assert!(lines.parse::<model::VisitJSON>("\u{feff}mark, id,user,location,visited_at\r").unwrap().is_none());
+        let visit: model::VisitJSON = lines.parse("5,1,2,3,-100").unwrap().unwrap();
+        assert_eq!((visit.id, visit.user, visit.location, visit.mark, visit.visited_at), (1, 2, 3, 5, -100));
+
+        assert!(lines.parse::<model::VisitJSON>("  ").unwrap().is_none());
+        assert_eq!(lines.parse::<model::VisitJSON>("5,1,2").unwrap_err(), "3 values, 5 expected");
+        assert!(lines.parse::<model::VisitJSON>("5,x,2,3,4").unwrap_err().contains("invalid number"));
+
+        let mut lines = Lines::new(Kind::Locations, Format::Ndjson);
+        let location: model::LocationJSON = lines
+            .parse(r#"{"id": 1, "distance": 2, "city": "a", "country": "b", "place": "c"}"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(location.place, "c");
+    }
+
+    #[test]
+    fn numbers_the_invalid_lines() {
+        let data = b"id,email,first_name,last_name,gender,birth_date\n1,a@b,A,B,m,0\n\n2,b@b,A\n";
+
+        let mut ids = Vec::new();
+        let err = for_each(data, Kind::Users, Format::Csv, |u: model::UserJSON| ids.push(u.id)).unwrap_err();
+        assert_eq!(err, "line 4: 3 values, 6 expected");
+        assert_eq!(ids, vec![1]);
+    }
+}
@@ -1,233 +1,777 @@
-use std::{error::Error, fs, path::Path, io::Read};
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt,
+    fs::{self, File},
+    io::{self, BufReader, Read},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::Instant,
+};
+
+use flate2::read::GzDecoder;
+use serde::{
+    de::{self, DeserializeOwned, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor},
+    Serialize,
+};
 use zip::ZipArchive;
 
-use crate::{model, storage::Storage};
+use crate::{
+    import::{self, Format},
+    model,
+    options::Options,
+    storage::{Counts, StoreError, Storage},
+};
 
-const DATA_FILE: &str = "data.zip";
 const OPTIONS_FILE: &str = "options.txt";
-const TEMP_DIR: &str = "tmp/data";
-const DATA_DIR: &str = "data";
 
-// unzip data.zip, read the timestamp from options.txt
-// counts all entries (users, visits, locations) from json files
-// stores all the entries to in-memory storage
-pub fn run(storage: &mut Storage) -> Result<(), Box<dyn Error>> {
-    storage.timestamp = get_timestamp();
+// number of the ids of the rejected entities kept in the report per reason
+const REPORT_IDS: usize = 10;
+
+// number of the parsed entities passed to the storing thread at once
+const CHUNK: usize = 4096;
+
+// what the load has stored and what it has rejected
+#[derive(Serialize, Default)]
+pub struct Report {
+    pub source: String,
+    pub files: usize,
+    pub users: usize,
+    pub locations: usize,
+    pub visits: usize,
+    pub rejected: BTreeMap<StoreError, Rejected>,
+    // duration of the load
+    pub seconds: f64,
+}
 
-    extract_json_files()?;
+#[derive(Serialize, Default)]
+pub struct Rejected {
+    pub count: usize,
+    // the first ids of the rejected entities, the visit ids for the visits
+    // of unknown users or locations
+    pub ids: Vec<u32>,
+}
 
-    // counts all the entries to preallocate vectors with predefined length
-    let locations_cnt = count_locations()?;
-    let users_cnt = count_users()?;
-    let visits_cnt = count_visits()?;
+impl Rejected {
+    pub fn add(&mut self, id: u32) {
+        self.count += 1;
+        if self.ids.len() < REPORT_IDS {
+            self.ids.push(id);
+        }
+    }
+}
 
-    store_locations(storage, locations_cnt)?;
-    store_users(storage, users_cnt)?;
-    store_visits(storage, visits_cnt)?;
+impl Report {
+    fn reject(&mut self, id: u32, reason: StoreError) {
+        self.rejected.entry(reason).or_default().add(id);
+    }
 
-    Ok(())
+    // whether all the entities of the data are stored
+    pub fn is_clean(&self) -> bool {
+        self.rejected.is_empty()
+    }
 }
 
-fn get_timestamp() -> i64 {
-    let mut file = fs::File::open(TEMP_DIR.to_owned() + "/" + OPTIONS_FILE).unwrap();
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "load report: {}, {} files, users {}, locations {}, visits {}, {:.3}s",
+            self.source, self.files, self.users, self.locations, self.visits, self.seconds
+        )?;
+        for (reason, rejected) in &self.rejected {
+            write!(f, "\n  rejected {}: {}, ids {:?}", reason, rejected.count, rejected.ids)?;
+        }
+        Ok(())
+    }
+}
 
-    let mut buf = "".to_string();
+// progress of the load for GET /ready
+#[derive(Default)]
+pub struct Progress {
+    // what the load is doing, e.g. "files" or "indexes"
+    phase: Mutex<&'static str>,
+    // bytes of the data files read so far and in total,
+    // the compressed ones for tar.gz
+    done: AtomicU64,
+    total: AtomicU64,
+}
 
-    _ = file.read_to_string(&mut buf).unwrap();
+impl Progress {
+    pub fn phase(&self) -> &'static str {
+        *self.phase.lock().unwrap()
+    }
 
-    let timestamp_str = buf.lines().next().unwrap();
-    let timestamp: i64 = timestamp_str.parse().unwrap();
+    pub fn set_phase(&self, phase: &'static str) {
+        *self.phase.lock().unwrap() = phase;
+    }
+
+    // percent of the data files read, None before the files are listed
+    pub fn percent(&self) -> Option<u64> {
+        match self.total.load(Ordering::Relaxed) {
+            0 => None,
+            total => Some((self.done.load(Ordering::Relaxed) * 100 / total).min(100)),
+        }
+    }
 
-    timestamp
+    fn add(&self, bytes: u64) {
+        self.done.fetch_add(bytes, Ordering::Relaxed);
+    }
 }
 
-fn extract_json_files() -> Result<(), Box<dyn Error>> {
-    let exist = Path::new(DATA_DIR).exists();
-    if !exist {
-        fs::create_dir_all(DATA_DIR)?;
+// stores all the entities from the data (a zip or tar.gz archive,
+// or a directory) to the storage with the timestamp of the options. the json files are
+// read to memory and parsed in parallel, every file once, and the parsed entities
+// are passed in chunks as they are parsed and stored by this thread in any order: the storage is in the bulk load mode,
+// so the visits may come before their users and locations. the visit indexes
+// are built and sorted in parallel after all the files are stored.
+// the entities the storage rejects are counted in the returned report
+pub fn run(
+    storage: &mut dyn Storage,
+    data: &Path,
+    options: &Options,
+    progress: &Progress,
+) -> Result<Report, Box<dyn Error>> {
+    let source = Source::open(data)?;
+
+    progress.total.store(source.size()?, Ordering::Relaxed);
+    progress.set_phase("files");
+
+    storage.set_timestamp(options.timestamp);
+
+    let threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+
+    let mut reserved = Counts::default();
+    let mut report = Report {
+        source: data.display().to_string(),
+        ..Report::default()
+    };
+
+    let start = Instant::now();
+
+    storage.begin_bulk_load();
+
+    parse_parallel(&source, threads, progress, |batch| {
+        match batch {
+            Batch::FileEnd => report.files += 1,
+            Batch::Users(users) => {
+                for user in users {
+                    reserve(storage, &mut reserved.users, user.id, |n| Counts {
+                        users: n,
+                        ..Counts::default()
+                    });
+                    if let Err(e) = storage.store_user(&user) {
+                        report.reject(user.id, e);
+                    }
+                }
+            }
+            Batch::Locations(locations) => {
+                for location in locations {
+                    reserve(storage, &mut reserved.locations, location.id, |n| Counts {
+                        locations: n,
+                        ..Counts::default()
+                    });
+                    if let Err(e) = storage.store_location(&location) {
+                        report.reject(location.id, e);
+                    }
+                }
+            }
+            Batch::Visits(visits) => {
+                for visit in visits {
+                    reserve(storage, &mut reserved.visits, visit.id, |n| Counts {
+                        visits: n,
+                        ..Counts::default()
+                    });
+                    if let Err(e) = storage.store_visit(&visit) {
+                        report.reject(visit.id, e);
+                    }
+                }
+            }
+        }
+    })?;
+
+    let files_time = start.elapsed();
+    let indexes_start = Instant::now();
+    progress.set_phase("indexes");
+
+    for (id, reason) in storage.end_bulk_load(threads) {
+        report.reject(id, reason);
     }
 
-    let file = fs::File::open(TEMP_DIR.to_owned() + "/" + DATA_FILE)?;
-    let mut archive = ZipArchive::new(file)?;
+    let indexes_time = indexes_start.elapsed();
 
-    for i in 0..archive.len() {
-        let mut file = archive.by_index(i)?;
+    info!(
+        "load: {} threads, files {:?}, indexes {:?}",
+        threads, files_time, indexes_time
+    );
 
-        if file.name().ends_with("json") {
-            let outpath = Path::new(DATA_DIR).join(file.mangled_name());
-            let mut outfile = fs::File::create(&outpath)?;
-            std::io::copy(&mut file, &mut outfile)?;
-        }
+    let counts = storage.counts();
+    report.users = counts.users;
+    report.locations = counts.locations;
+    report.visits = counts.visits;
+    report.seconds = start.elapsed().as_secs_f64();
+
+    Ok(report)
+}
+
+// options.txt of the data is looked for next to the archive
+// or in the directory unless its path is given
+pub fn options_path(data: &Path, options: Option<&Path>) -> PathBuf {
+    match options {
+        Some(path) => path.to_path_buf(),
+        None if data.is_dir() => data.join(OPTIONS_FILE),
+        None => data.with_file_name(OPTIONS_FILE),
     }
+}
 
-    Ok(())
+// where the data files are read from
+enum Source {
+    // the entries of the archive with the kinds and the formats of their files
+    Zip(PathBuf, Vec<(usize, Kind, Format)>),
+    // tar.gz can only be read sequentially, so its entries are found while reading
+    TarGz(PathBuf),
+    Dir(Vec<(PathBuf, Kind, Format)>),
 }
 
-fn store_users(storage: &mut Storage, users_count: u32) -> Result<(), Box<dyn Error>> {
-    storage
-        .users
-        .resize_with(users_count as usize + 1, model::User::default);
+impl Source {
+    // the kind of the source is detected by the contents, not the extension
+    fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        if path.is_dir() {
+            let mut files = Vec::new();
+            list_dir(path, &mut files)?;
+            files.sort();
+            return Ok(Source::Dir(files));
+        }
 
-    let files = fs::read_dir(DATA_DIR)?;
+        let mut magic = [0u8; 2];
+        File::open(path)
+            .and_then(|mut f| f.read_exact(&mut magic))
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
 
-    for file in files {
-        let path = file?.path();
+        match &magic {
+            b"PK" => {
+                let mut archive = ZipArchive::new(File::open(path)?)?;
 
-        let ext = path.extension().ok_or("extension error")?.to_str();
+                let mut entries = Vec::new();
+                for i in 0..archive.len() {
+                    if let Some((kind, format)) = Kind::of_file(archive.by_index_raw(i)?.name()) {
+                        entries.push((i, kind, format));
+                    }
+                }
 
-        if ext == Some("json") {
-            let f_name = path.to_string_lossy();
-            if f_name.contains(&"users") {
-                let data = fs::read_to_string(path.as_os_str())?;
-                let users_file_data: model::UsersDataJSON = serde_json::from_str(&data)?;
+                Ok(Source::Zip(path.to_path_buf(), entries))
+            }
+            [0x1f, 0x8b] => Ok(Source::TarGz(path.to_path_buf())),
+            _ => Err(format!("{}: not a zip or tar.gz archive", path.display()).into()),
+        }
+    }
 
-                for user in users_file_data.users {
-                    let _ = storage.store_user(
-                        user.id as usize,
-                        user.email.as_str(),
-                        user.first_name.as_str(),
-                        user.last_name.as_str(),
-                        user.birth_date,
-                        user.gender.as_str(),
-                    );
+    // size of the entity files to read, the compressed size for tar.gz
+    fn size(&self) -> Result<u64, Box<dyn Error>> {
+        Ok(match self {
+            Source::Zip(path, entries) => {
+                let mut archive = ZipArchive::new(File::open(path)?)?;
+                let mut size = 0;
+                for (i, _, _) in entries {
+                    size += archive.by_index_raw(*i)?.size();
                 }
+                size
             }
+            Source::TarGz(path) => fs::metadata(path)?.len(),
+            Source::Dir(files) => {
+                let mut size = 0;
+                for (path, _, _) in files {
+                    size += fs::metadata(path)?.len();
+                }
+                size
+            }
+        })
+    }
+}
+
+// collects the entity files of the directory and its subdirectories
+// the links to the directories aren't followed, so a link loop can't recurse forever
+fn list_dir(dir: &Path, files: &mut Vec<(PathBuf, Kind, Format)>) -> Result<(), Box<dyn Error>> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+
+        if entry.file_type()?.is_dir() {
+            list_dir(&path, files)?;
+        } else if let Some((kind, format)) = path.to_str().and_then(Kind::of_file) {
+            files.push((path, kind, format));
         }
     }
 
     Ok(())
 }
 
-pub fn count_users() -> Result<u32, Box<dyn Error>> {
-    let files = fs::read_dir(DATA_DIR)?;
-    let mut cnt: u32 = 0;
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Kind {
+    Users,
+    Locations,
+    Visits,
+}
+
+impl Kind {
+    // name of the array in the json file
+    pub fn key(self) -> &'static str {
+        match self {
+            Kind::Users => "users",
+            Kind::Locations => "locations",
+            Kind::Visits => "visits",
+        }
+    }
 
-    for file in files {
-        let path = file?.path();
+    // kind of the entities and the format of the file by its base name:
+    // <kind>.<ext> or <kind>_<number>.<ext>, e.g. users_1.json or visits.csv.
+    // the directories in the path don't matter
+    fn of_file(path: &str) -> Option<(Self, Format)> {
+        let name = path.rsplit(['/', '\\']).next()?;
+        let (stem, ext) = name.rsplit_once('.')?;
+        let format = Format::of_extension(ext)?;
+
+        [Kind::Users, Kind::Locations, Kind::Visits]
+            .into_iter()
+            .find(|kind| match stem.strip_prefix(kind.key()) {
+                Some("") => true,
+                Some(num) => num
+                    .strip_prefix('_')
+                    .map(|num| !num.is_empty() && num.bytes().all(|b| b.is_ascii_digit()))
+                    .unwrap_or(false),
+                None => false,
+            })
+            .map(|kind| (kind, format))
+    }
+}
 
-        let ext = path.extension().ok_or("extension error")?.to_str();
+impl std::str::FromStr for Kind {
+    type Err = ();
 
-        if ext == Some("json") {
-            let f_name = path.to_string_lossy();
-            if f_name.contains(&"users") {
-                let data = fs::read_to_string(path.as_os_str())?;
-                let users_file_data: model::UsersDataJSON = serde_json::from_str(&data)?;
-                cnt = cnt + users_file_data.users.len() as u32;
-            }
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "users" => Ok(Kind::Users),
+            "locations" => Ok(Kind::Locations),
+            "visits" => Ok(Kind::Visits),
+            _ => Err(()),
         }
     }
+}
 
-    Ok(cnt)
+// chunk of the parsed entities of a file
+enum Batch {
+    Users(Vec<model::UserJSON>),
+    Locations(Vec<model::LocationJSON>),
+    Visits(Vec<model::VisitJSON>),
+    // all the entities of the file are passed
+    FileEnd,
 }
 
-fn store_visits(storage: &mut Storage, visits_count: u32) -> Result<(), Box<dyn Error>> {
-    storage
-        .visits
-        .resize_with(visits_count as usize + 1, model::Visit::default);
+// parses the files of the source on the threads. the parsed entities
+// are passed to store in chunks as they are parsed, in no particular order
+fn parse_parallel<F>(source: &Source, threads: usize, progress: &Progress, mut store: F) -> Result<(), Box<dyn Error>>
+where
+    F: FnMut(Batch),
+{
+    let next = AtomicUsize::new(0);
+
+    // bounded, so the parsed files don't pile up if storing is slower
+    let (tx, rx) = mpsc::sync_channel::<Result<Batch, String>>(threads);
+
+    // files of tar.gz read by a single thread for the parsing threads. the receiver
+    // is owned by the parsing threads, so the reader stops once they all have exited,
+    // e.g. on an error, instead of waiting to send the next file forever
+    let (files_tx, files_rx) = mpsc::sync_channel::<TarFile>(threads);
+    let files_rx = Arc::new(Mutex::new(files_rx));
+
+    thread::scope(|scope| {
+        if let Source::TarGz(path) = source {
+            scope.spawn(move || {
+                if let Err(e) = read_tar_gz(path, &files_tx, progress) {
+                    let _ = files_tx.send(Err(e.to_string()));
+                }
+            });
+        } else {
+            drop(files_tx);
+        }
 
-    let files = fs::read_dir(DATA_DIR)?;
+        for _ in 0..threads {
+            let tx = tx.clone();
+            let next = &next;
+            let files_rx = files_rx.clone();
+
+            scope.spawn(move || {
+                let res = (|| -> Result<(), Box<dyn Error>> {
+                    let mut archive = match source {
+                        Source::Zip(path, _) => Some(ZipArchive::new(File::open(path)?)?),
+                        _ => None,
+                    };
+
+                    // the file is read to the reused buffer, parsing from a slice
+                    // is much faster than from a reader
+                    let mut buf = Vec::new();
+
+                    loop {
+                        let (kind, format) = match source {
+                            Source::Zip(_, entries) => {
+                                let Some(&(i, kind, format)) = entries.get(next.fetch_add(1, Ordering::Relaxed)) else {
+                                    break;
+                                };
+                                buf.clear();
+                                archive.as_mut().unwrap().by_index(i)?.read_to_end(&mut buf)?;
+                                progress.add(buf.len() as u64);
+                                (kind, format)
+                            }
+                            Source::Dir(files) => {
+                                let Some((path, kind, format)) = files.get(next.fetch_add(1, Ordering::Relaxed)) else {
+                                    break;
+                                };
+                                buf.clear();
+                                File::open(path)?.read_to_end(&mut buf)?;
+                                progress.add(buf.len() as u64);
+                                (*kind, *format)
+                            }
+                            Source::TarGz(_) => {
+                                let file = files_rx.lock().unwrap().recv();
+                                let Ok(file) = file else {
+                                    break;
+                                };
+                                let (kind, format, data) = file?;
+                                buf = data;
+                                (kind, format)
+                            }
+                        };
+
+                        let sent = match kind {
+                            Kind::Users => send_file(&buf, kind, format, &tx, Batch::Users)?,
+                            Kind::Locations => send_file(&buf, kind, format, &tx, Batch::Locations)?,
+                            Kind::Visits => send_file(&buf, kind, format, &tx, Batch::Visits)?,
+                        };
+
+                        if !sent {
+                            // the receiver has failed
+                            break;
+                        }
+                    }
+                    Ok(())
+                })();
+
+                if let Err(e) = res {
+                    let _ = tx.send(Err(e.to_string()));
+                }
+            });
+        }
+        drop(tx);
+        drop(files_rx);
 
-    for file in files {
-        let path = file?.path();
+        for batch in rx {
+            store(batch?);
+        }
 
-        let ext = path.extension().ok_or("extension error")?.to_str();
+        Ok(())
+    })
+}
 
-        if ext == Some("json") {
-            let f_name = path.to_string_lossy();
-            if f_name.contains(&"visits") {
-                let data = fs::read_to_string(path.as_os_str())?;
-                let visits_file_data: model::VisitsDataJSON = serde_json::from_str(&data)?;
+// the entity file of tar.gz with its kind and format, or the read error
+type TarFile = Result<(Kind, Format, Vec<u8>), String>;
+
+// reads the entity files of tar.gz one by one and sends them to the parsing threads
+fn read_tar_gz(path: &Path, files: &mpsc::SyncSender<TarFile>, progress: &Progress) -> Result<(), Box<dyn Error>> {
+    let file = Counting {
+        r: File::open(path)?,
+        progress,
+    };
+    let mut archive = tar::Archive::new(GzDecoder::new(BufReader::new(file)));
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
 
-                for visit in visits_file_data.visits {
-                    storage.store_visit(
-                        visit.id,
-                        visit.user,
-                        visit.location,
-                        visit.visited_at,
-                        visit.mark,
-                    );
-                }
-            }
+        let (kind, format) = match entry.path()?.to_str().and_then(Kind::of_file) {
+            Some(file) => file,
+            None => continue,
+        };
+
+        let mut data = Vec::with_capacity(entry.size() as usize);
+        entry.read_to_end(&mut data)?;
+
+        if files.send(Ok((kind, format, data))).is_err() {
+            // the parsing threads have failed
+            break;
         }
     }
 
     Ok(())
 }
 
-pub fn count_visits() -> Result<u32, Box<dyn Error>> {
-    let files = fs::read_dir(DATA_DIR)?;
-    let mut cnt: u32 = 0;
-
-    for file in files {
-        let path = file?.path();
+// adds the bytes read to the progress
+struct Counting<'a, R> {
+    r: R,
+    progress: &'a Progress,
+}
 
-        let ext = path.extension().ok_or("extension error")?.to_str();
+impl<R: Read> Read for Counting<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.r.read(buf)?;
+        self.progress.add(n as u64);
+        Ok(n)
+    }
+}
 
-        if ext == Some("json") {
-            let f_name = path.to_string_lossy();
-            if f_name.contains(&"visits") {
-                let data = fs::read_to_string(path.as_os_str())?;
-                let visits_file_data: model::VisitsDataJSON = serde_json::from_str(&data)?;
-                cnt = cnt + visits_file_data.visits.len() as u32;
-            }
+// parses the file and sends its entities in chunks followed by Batch::FileEnd,
+// so only a chunk of them is in the memory at once.
+// returns false if the receiver has failed
+fn send_file<T: DeserializeOwned>(
+    data: &[u8],
+    kind: Kind,
+    format: Format,
+    tx: &mpsc::SyncSender<Result<Batch, String>>,
+    batch: fn(Vec<T>) -> Batch,
+) -> Result<bool, Box<dyn Error>> {
+    let mut chunk = Vec::with_capacity(CHUNK);
+    let mut sent = true;
+
+    let mut f = |entity| {
+        chunk.push(entity);
+        if chunk.len() == CHUNK {
+            let entities = std::mem::replace(&mut chunk, Vec::with_capacity(CHUNK));
+            sent = sent && tx.send(Ok(batch(entities))).is_ok();
         }
+    };
+
+    if format == Format::Json {
+        for_each(data, kind.key(), &mut f)?;
+    } else {
+        import::for_each(data, kind, format, &mut f)?;
     }
 
-    Ok(cnt)
+    Ok(sent && tx.send(Ok(batch(chunk))).is_ok() && tx.send(Ok(Batch::FileEnd)).is_ok())
 }
 
-fn store_locations(storage: &mut Storage, locations_count: u32) -> Result<(), Box<dyn Error>> {
-    storage
-        .locations
-        .resize_with(locations_count as usize + 1, model::Location::default);
+// the storage is sized from the max id seen so far, doubling it,
+// so it's resized only a few times while loading
+fn reserve(storage: &mut dyn Storage, reserved: &mut usize, id: u32, counts: impl Fn(usize) -> Counts) {
+    let needed = id as usize + 1;
+    if needed > *reserved {
+        *reserved = needed.next_power_of_two();
+        storage.reserve(counts(*reserved));
+    }
+}
 
-    let files = fs::read_dir(DATA_DIR)?;
+// parses the json file {"<key>": [...]} calling f for every element of the array,
+// the array itself is never collected
+pub fn for_each<T, F>(data: &[u8], key: &str, f: F) -> Result<(), serde_json::Error>
+where
+    T: DeserializeOwned,
+    F: FnMut(T),
+{
+    let mut de = serde_json::Deserializer::from_slice(data);
+    de::Deserializer::deserialize_map(
+        &mut de,
+        FileVisitor {
+            key,
+            f,
+            marker: PhantomData,
+        },
+    )?;
+    de.end()
+}
 
-    for file in files {
-        let path = file?.path();
+struct FileVisitor<'k, T, F> {
+    key: &'k str,
+    f: F,
+    marker: PhantomData<T>,
+}
 
-        let ext = path.extension().ok_or("extension error")?.to_str();
+impl<'de, T, F> Visitor<'de> for FileVisitor<'_, T, F>
+where
+    T: DeserializeOwned,
+    F: FnMut(T),
+{
+    type Value = ();
 
-        if ext == Some("json") {
-            let f_name = path.to_string_lossy();
-            if f_name.contains(&"locations") {
-                let data = fs::read_to_string(path.as_os_str())?;
-                let locations_file_data: model::LocationsDataJSON = serde_json::from_str(&data)?;
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "an object with the \"{}\" array", self.key)
+    }
 
-                for location in locations_file_data.locations {
-                    storage.store_location(
-                        location.id as usize,
-                        &location.country,
-                        &location.city,
-                        &location.place,
-                        location.distance,
-                    );
-                }
+    fn visit_map<A: MapAccess<'de>>(mut self, mut map: A) -> Result<(), A::Error> {
+        while let Some(key) = map.next_key::<String>()? {
+            if key == self.key {
+                map.next_value_seed(ArraySeed {
+                    f: &mut self.f,
+                    marker: PhantomData,
+                })?;
+            } else {
+                map.next_value::<IgnoredAny>()?;
             }
         }
+        Ok(())
     }
+}
 
-    Ok(())
+struct ArraySeed<'f, T, F> {
+    f: &'f mut F,
+    marker: PhantomData<T>,
 }
 
-pub fn count_locations() -> Result<u32, Box<dyn Error>> {
-    let files = fs::read_dir(DATA_DIR)?;
-    let mut cnt: u32 = 0;
+impl<'de, T, F> DeserializeSeed<'de> for ArraySeed<'_, T, F>
+where
+    T: DeserializeOwned,
+    F: FnMut(T),
+{
+    type Value = ();
 
-    for file in files {
-        let path = file?.path();
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
 
-        let ext = path.extension().ok_or("extension error")?.to_str();
+impl<'de, T, F> Visitor<'de> for ArraySeed<'_, T, F>
+where
+    T: DeserializeOwned,
+    F: FnMut(T),
+{
+    type Value = ();
 
-        if ext == Some("json") {
-            let f_name = path.to_string_lossy();
-            if f_name.contains(&"locations") {
-                let data = fs::read_to_string(path.as_os_str())?;
-                let locations_file_data: model::LocationsDataJSON = serde_json::from_str(&data)?;
-                cnt = cnt + locations_file_data.locations.len() as u32;
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an array of entities")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(entity) = seq.next_element::<T>()? {
+            (self.f)(entity);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sends_the_file_in_chunks() {
+        let visits: Vec<_> = (1..=CHUNK as u32 * 2 + 1)
+            .map(|id| model::VisitJSON {
+                id,
+                ..Default::default()
+            })
+            .collect();
+        let data = serde_json::to_vec(&serde_json::json!({ "other": [1], "visits": visits })).unwrap();
+
+        let (tx, rx) = mpsc::sync_channel(8);
+        assert!(send_file(&data, Kind::Visits, Format::Json, &tx, Batch::Visits).unwrap());
+        drop(tx);
+
+        let mut sizes = Vec::new();
+        let mut last_id = 0;
+        for batch in rx {
+            match batch.unwrap() {
+                Batch::Visits(visits) => {
+                    sizes.push(visits.len());
+                    last_id = visits.last().map(|v| v.id).unwrap_or(last_id);
+                }
+                Batch::FileEnd => sizes.push(0),
+                _ => panic!("unexpected batch"),
             }
         }
+        assert_eq!(sizes, vec![CHUNK, CHUNK, 1, 0]);
+        assert_eq!(last_id, CHUNK as u32 * 2 + 1);
+
+        // the receiver is gone
+        let (tx, rx) = mpsc::sync_channel(8);
+        drop(rx);
+        assert!(!send_file(&data, Kind::Visits, Format::Json, &tx, Batch::Visits).unwrap());
+    }
+
+    #[test]
+    fn detects_the_files_by_name() {
+        assert_eq!(Kind::of_file("users_1.json"), Some((Kind::Users, Format::Json)));
+        assert_eq!(Kind::of_file("data/visits.csv"), Some((Kind::Visits, Format::Csv)));
+        assert_eq!(Kind::of_file("a\\b\\locations_12.jsonl"), Some((Kind::Locations, Format::Ndjson)));
+        assert_eq!(Kind::of_file("users_2.ndjson"), Some((Kind::Users, Format::Ndjson)));
+        // the directories don't matter
+        assert_eq!(Kind::of_file("users/visits_3.json"), Some((Kind::Visits, Format::Json)));
+
+        assert_eq!(Kind::of_file("users_.json"), None);
+        assert_eq!(Kind::of_file("users_1a.json"), None);
+        assert_eq!(Kind::of_file("users1.json"), None);
+        assert_eq!(Kind::of_file("old_users.json"), None);
+        assert_eq!(Kind::of_file("users.txt"), None);
+        assert_eq!(Kind::of_file("options.txt"), None);
+        assert_eq!(Kind::of_file("users"), None);
     }
 
-    Ok(cnt)
+    // the files of the dataset: the visit comes before its user and location
+    fn files() -> Vec<(&'static str, &'static str)> {
+        vec![
+            ("data/visits_1.json", r#"{"visits": [{"id": 1, "user": 1, "location": 2, "mark": 3, "visited_at": 100}]}"#),
+            ("data/more/users.csv", "id,email,first_name,last_name,gender,birth_date\n1,a@b.ru,\"Ann, \"\"A\"\"\",Li,f,0\n"),
+            ("data/locations_1.ndjson", r#"{"id": 2, "distance": 5, "city": "C", "country": "R", "place": "P"}"#),
+            ("data/readme.md", "not an entity file"),
+        ]
+    }
+
+    fn check(s: &dyn Storage, report: &Report) {
+        assert!(report.is_clean());
+        assert_eq!((report.files, report.users, report.locations, report.visits), (3, 1, 1, 1));
+        assert_eq!(s.user(1).unwrap().first_name, "Ann, \"A\"");
+
+        let params = model::LocationAvgParams {
+            from_date: None,
+            to_date: None,
+            from_age: None,
+            to_age: None,
+            gender: None,
+        };
+        assert_eq!(s.location_avg(2, &params), Ok(3.0));
+    }
+
+    #[test]
+    fn loads_a_directory_and_tar_gz() {
+        let root = std::env::temp_dir().join(format!("hlcup-load-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+
+        let mut tar = tar::Builder::new(flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast()));
+        for (name, data) in files() {
+            let path = root.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, data).unwrap();
+
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, name, data.as_bytes()).unwrap();
+        }
+        // the link loop isn't followed
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(root.join("data"), root.join("data/more/loop")).unwrap();
+
+        // the kind of the archive is detected by the contents
+        let archive = root.join("data.zip");
+        fs::write(&archive, tar.into_inner().unwrap().finish().unwrap()).unwrap();
+
+        let options = Options::parse("1500000000").unwrap();
+
+        for path in [root.join("data"), archive.clone()] {
+            let mut s = crate::storage::MemStorage::new();
+            let report = run(&mut s, &path, &options, &Progress::default()).unwrap();
+            check(&s, &report);
+        }
+
+        fs::write(&archive, b"plain text").unwrap();
+        let mut s = crate::storage::MemStorage::new();
+        let err = run(&mut s, &archive, &options, &Progress::default()).err().unwrap();
+        assert!(err.to_string().contains("not a zip or tar.gz archive"));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
 }
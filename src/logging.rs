@@ -0,0 +1,248 @@
+use std::{
+    fmt::{self, Write as _},
+    io::{self, Write as _},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+
+use actix_web::{body::{BodySize, MessageBody}, dev::ServiceResponse, http::Method};
+use chrono::Utc;
+use serde_json::{json, Value};
+
+use crate::config::Config;
+
+// the log records of the server, a line per record on stdout.
+// the output of the commands, e.g. of recover or --print-config, isn't logged
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    fn name(&self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    // the time, the level, the message and the fields as in logfmt
+    Text,
+    Logfmt,
+    // an object per line
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "logfmt" => Ok(Format::Logfmt),
+            "json" => Ok(Format::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+struct Logger {
+    level: Level,
+    format: Format,
+    access_log: bool,
+    // every n-th request of the high-qps routes is logged
+    access_log_sample: u64,
+}
+
+// the records before init, e.g. the config errors
+const DEFAULT: Logger = Logger {
+    level: Level::Info,
+    format: Format::Text,
+    access_log: false,
+    access_log_sample: 1,
+};
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+// the sampled requests so far
+static SAMPLED: AtomicU64 = AtomicU64::new(0);
+
+fn logger() -> &'static Logger {
+    LOGGER.get().unwrap_or(&DEFAULT)
+}
+
+pub fn init(config: &Config) {
+    let _ = LOGGER.set(Logger {
+        level: config.log_level,
+        format: config.log_format,
+        access_log: config.access_log,
+        access_log_sample: config.access_log_sample.max(1),
+    });
+}
+
+pub fn enabled(level: Level) -> bool {
+    level <= logger().level
+}
+
+// writes the record with the fields, the null fields are skipped
+pub fn log(level: Level, msg: fmt::Arguments, fields: &[(&str, Value)]) {
+    if enabled(level) {
+        write(level, msg, fields);
+    }
+}
+
+// the audit records are written at any level
+pub fn audit(fields: &[(&str, Value)]) {
+    write(Level::Info, format_args!("audit"), fields);
+}
+
+fn write(level: Level, msg: fmt::Arguments, fields: &[(&str, Value)]) {
+    let logger = logger();
+
+    let time = Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ");
+    let fields = fields.iter().filter(|(_, value)| !value.is_null());
+
+    let mut line = String::new();
+    match logger.format {
+        Format::Text => {
+            let _ = write!(line, "{} {:5} {}", time, level.name().to_uppercase(), msg);
+            for (key, value) in fields {
+                let _ = write!(line, " {}={}", key, logfmt_value(value));
+            }
+        }
+        Format::Logfmt => {
+            let msg = Value::String(msg.to_string());
+            let _ = write!(line, "time={} level={} msg={}", time, level.name(), logfmt_value(&msg));
+            for (key, value) in fields {
+                let _ = write!(line, " {}={}", key, logfmt_value(value));
+            }
+        }
+        // written by hand to keep the time, the level and the message first
+        Format::Json => {
+            let msg = Value::String(msg.to_string());
+            let _ = write!(line, "{{\"time\":\"{}\",\"level\":\"{}\",\"msg\":{}", time, level.name(), msg);
+            for (key, value) in fields {
+                let _ = write!(line, ",{}:{}", Value::String(key.to_string()), value);
+            }
+            line.push('}');
+        }
+    }
+    line.push('\n');
+
+    // a single write, so the records of the threads aren't mixed
+    let _ = io::stdout().lock().write_all(line.as_bytes());
+}
+
+// the strings with the spaces, quotes or control characters are quoted
+fn logfmt_value(value: &Value) -> String {
+    match value {
+        Value::String(s)
+            if !s.is_empty()
+                && !s.chars().any(|c| c == ' ' || c == '"' || c == '=' || c.is_control()) =>
+        {
+            s.clone()
+        }
+        value => value.to_string(),
+    }
+}
+
+// logs the served request if the access log is enabled. the GET requests
+// of the entities are sampled unless they fail, the others are logged all
+pub fn access<B: MessageBody>(res: &ServiceResponse<B>, elapsed: Duration) {
+    let logger = logger();
+    if !logger.access_log || !enabled(Level::Info) {
+        return;
+    }
+
+    let req = res.request();
+    let status = res.status();
+
+    let high_qps = req.method() == Method::GET
+        && ["/users/", "/locations/", "/visits/"]
+            .iter()
+            .any(|prefix| req.path().starts_with(prefix));
+
+    if high_qps
+        && !status.is_server_error()
+        && !SAMPLED
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(logger.access_log_sample)
+    {
+        return;
+    }
+
+    // the size of the streamed bodies isn't known
+    let bytes = match res.response().body().size() {
+        BodySize::Sized(n) => json!(n),
+        _ => Value::Null,
+    };
+    let query = match req.query_string() {
+        "" => Value::Null,
+        query => json!(query),
+    };
+
+    log(
+        Level::Info,
+        format_args!("request"),
+        &[
+            ("method", json!(req.method().as_str())),
+            ("path", json!(req.path())),
+            ("query", query),
+            ("status", json!(status.as_u16())),
+            ("latency_ms", json!(elapsed.as_micros() as f64 / 1000.0)),
+            ("bytes", bytes),
+            ("sampled", json!(high_qps && logger.access_log_sample > 1)),
+        ],
+    );
+}
+
+macro_rules! error {
+    ($($arg:tt)+) => {
+        $crate::logging::log($crate::logging::Level::Error, format_args!($($arg)+), &[])
+    };
+}
+
+macro_rules! warn {
+    ($($arg:tt)+) => {
+        $crate::logging::log($crate::logging::Level::Warn, format_args!($($arg)+), &[])
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => {
+        $crate::logging::log($crate::logging::Level::Info, format_args!($($arg)+), &[])
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => {
+        $crate::logging::log($crate::logging::Level::Debug, format_args!($($arg)+), &[])
+    };
+}
@@ -1,55 +1,508 @@
+// the logging macros are used by all the modules below
+#[macro_use]
+pub mod logging;
+
+pub mod auth;
+pub mod cache;
+pub mod config;
 pub mod dict;
+pub mod events;
+pub mod export;
+pub mod idvec;
+pub mod import;
 pub mod load;
+pub mod metrics;
 pub mod model;
+pub mod mutation;
+pub mod options;
+pub mod replication;
+pub mod recovery;
+pub mod reload;
+pub mod render;
+pub mod routes;
+pub mod shutdown;
+pub mod state;
 pub mod storage;
+pub mod wal;
+pub mod warmup;
 pub mod handlers_get;
 pub mod handlers_create;
+pub mod handlers_admin;
+pub mod handlers_events;
+pub mod handlers_replication;
+pub mod handlers_import;
+pub mod handlers_metrics;
+pub mod handlers_health;
 
-use actix_web::{web, App, HttpServer};
-use std::{process, time::Duration, sync::{Arc, RwLock}};
+use actix_web::{dev::Service, http::KeepAlive, web, App, HttpServer};
+use futures_util::future::{self, Either};
+use std::{env, error::Error, fs::{self, File}, io::{self, BufWriter}, path::Path, process, thread, time::{Duration, Instant}, sync::{atomic::AtomicBool, Arc, Mutex, OnceLock, RwLock}};
 
-struct AppState {
-    storage: Arc<RwLock<storage::Storage>>,
-}
+use config::{Config, StorageKind};
+use state::AppState;
+use storage::Storage;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let mut storage = storage::Storage::new();
+    let (config, args) = match Config::load(env::args().skip(1).collect()) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            error!("Config error: {}", e);
+            process::exit(1);
+        }
+    };
 
-    println!("loading data to in-memory storage");
+    logging::init(&config);
 
-    if let Err(e) = load::run(&mut storage) {
-        println!("Run error: {}", e);
-        process::exit(1);
+    if config.print_config {
+        config.print();
+        return Ok(());
     }
 
-    println!(
-        "loaded: users {}, visits {}, locations {}",
-        storage.users.len(),
-        storage.visits.len(),
-        storage.locations.len()
-    );
+    // `hlcup2017 recover (--to-seq N | --to-time T) [--dry-run]` restores the data and exits
+    if args.first().map(String::as_str) == Some("recover") {
+        if let Err(e) = run_recover(&config, &args[1..]) {
+            error!("Recovery error: {}", e);
+            process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let options = read_options(&config);
+    let mode = options.map(|o| o.mode).unwrap_or(options::RunMode::Test);
+
+    // `hlcup2017 export <out.zip> [--per-file N]` writes the data and exits
+    if args.first().map(String::as_str) == Some("export") {
+        let res = read_data(&config, options.as_ref())
+            .and_then(|storage| run_export(&*storage, mode, &args[1..]));
+        if let Err(e) = res {
+            error!("Export error: {}", e);
+            process::exit(1);
+        }
+        return Ok(());
+    }
 
-    println!("starting web server");
+    info!("starting web server");
 
-    let state = AppState { storage: Arc::new(RwLock::new(storage)) };
+    let state = AppState {
+        storage: Arc::new(RwLock::new(Box::new(storage::MemStorage::new()))),
+        cache: cache::ResponseCache::new(config.response_cache_mb(mode)),
+        wal: OnceLock::new(),
+        events: events::EventBus::new(0, config.events_buffer),
+        load_report: Mutex::new(None),
+        options: Mutex::new(options),
+        reload: Mutex::new(reload::Status::Idle),
+        captured: Mutex::new(None),
+        metrics: metrics::Metrics::new(),
+        ready: AtomicBool::new(false),
+        progress: load::Progress::default(),
+        config,
+    };
 
     let data = web::Data::new(state);
 
-    HttpServer::new(move || {
+    // the server answers /health and /ready while the data is loading
+    {
+        let data = data.clone();
+        thread::spawn(move || load_and_serve(data, mode));
+    }
+
+    let config = &data.config;
+
+    let keep_alive = match config.keep_alive_s {
+        0 => KeepAlive::Disabled,
+        s => KeepAlive::Timeout(Duration::from_secs(s)),
+    };
+    let json_limit = config.json_limit_kb * 1024;
+
+    let app_data = data.clone();
+
+    let mut server = HttpServer::new(move || {
+        let metrics_data = app_data.clone();
+
         App::new()
-            .app_data(data.clone())
-            .service(handlers_get::users)
-            .service(handlers_get::visits)
-            .service(handlers_get::locations)
-            .service(handlers_get::user_visits)
-            .service(handlers_get::location_avg)
-            .service(handlers_create::new_user)
-            .service(handlers_create::new_location)
-            .service(handlers_create::new_visit)
+            .app_data(app_data.clone())
+            .app_data(web::JsonConfig::default().limit(json_limit))
+            .configure(routes::configure)
+            .wrap_fn(move |req, srv| {
+                let start = Instant::now();
+                let data = metrics_data.clone();
+
+                let denied = match auth::check(&data.config.api_keys, &req) {
+                    Err(res) => Some(*res),
+                    Ok(()) if !data.is_ready() && !routes::of(&req).is_some_and(|r| r.while_loading) => {
+                        Some(handlers_health::loading(&data))
+                    }
+                    Ok(()) => None,
+                };
+
+                let res = match denied {
+                    None => Either::Left(srv.call(req)),
+                    Some(res) => Either::Right(future::ready(Ok(req.into_response(res)))),
+                };
+
+                async move {
+                    let res = res.await?;
+                    let elapsed = start.elapsed();
+                    data.metrics.request(&res, elapsed);
+                    logging::access(&res, elapsed);
+                    Ok(res)
+                }
+            })
     })
-    .keep_alive(Duration::from_secs(30))
-    .bind(("127.0.0.1", 8080))?
-    .run()
-    .await
-}
\ No newline at end of file
+    .keep_alive(keep_alive)
+    .backlog(config.backlog)
+    .max_connections(config.max_connections)
+    .shutdown_timeout(config.shutdown_timeout_s)
+    .disable_signals();
+
+    if let Some(workers) = config.workers {
+        server = server.workers(workers);
+    }
+    for addr in &config.bind {
+        server = server.bind(addr)?;
+        info!("listening on {}", addr);
+    }
+
+    let server = server.run();
+
+    // the signals are handled here instead of actix,
+    // so the deadline of the shutdown counts from the signal
+    let deadline = Arc::new(Mutex::new(None));
+    {
+        let handle = server.handle();
+        let deadline = deadline.clone();
+        let timeout = Duration::from_secs(config.shutdown_timeout_s);
+        let data = data.clone();
+
+        actix_web::rt::spawn(async move {
+            shutdown::signal().await;
+            info!("shutdown: draining the requests");
+            *deadline.lock().unwrap() = Some(Instant::now() + timeout);
+            data.events.close();
+            handle.stop(true).await;
+        });
+    }
+
+    server.await?;
+
+    let deadline = deadline.lock().unwrap().unwrap_or_else(Instant::now);
+    shutdown::persist(data, deadline);
+
+    Ok(())
+}
+
+// loads the data in the background and swaps the empty storage of the server
+// with it, then the data routes are served. the server exits if it fails
+fn load_and_serve(data: web::Data<AppState>, mode: options::RunMode) {
+    let options = *data.options.lock().unwrap();
+
+    let (storage, load_report, wal) = match open_data(&data.config, options.as_ref(), &data.progress) {
+        Ok(opened) => opened,
+        Err(e) => {
+            error!("Run error: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let counts = storage.counts();
+    info!(
+        "loaded: users {}, visits {}, locations {}",
+        counts.users, counts.visits, counts.locations
+    );
+
+    if data.config.warmup(mode) {
+        data.progress.set_phase("warmup");
+        warmup::run(&*storage);
+    }
+
+    data.progress.set_phase("cache");
+
+    if let Some(wal) = wal {
+        let _ = data.wal.set(Mutex::new(wal));
+    }
+    *data.load_report.lock().unwrap() = load_report;
+
+    // rebuilds the cached responses and resets the events to the loaded data
+    data.replace_storage(storage);
+    if data.cache.is_enabled() {
+        info!("rendered responses cache: {} MB", data.cache.size() / 1024 / 1024);
+    }
+
+    data.set_ready();
+    info!("ready");
+
+    if data.config.snapshot_dir.is_some() && data.config.snapshot_interval_s > 0 {
+        let data = data.clone();
+        let interval = Duration::from_secs(data.config.snapshot_interval_s);
+
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            match data.snapshot() {
+                Ok(path) => info!("snapshot written: {}", path.display()),
+                Err(e) => error!("snapshot error: {}", e),
+            }
+        });
+    }
+
+    if let Some(leader) = data.config.leader.clone() {
+        thread::spawn(move || replication::follow(data, leader));
+    }
+}
+
+fn run_recover(config: &Config, args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut target = None;
+    let mut dry_run = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--to-seq" => {
+                let seq = args.next().ok_or("--to-seq needs a value")?;
+                target = Some(recovery::Target::Seq(
+                    seq.parse().map_err(|_| "invalid --to-seq value")?,
+                ));
+            }
+            "--to-time" => {
+                let time = args.next().ok_or("--to-time needs a value")?;
+                target = Some(recovery::Target::Time(recovery::parse_time(time)?));
+            }
+            "--dry-run" => dry_run = true,
+            arg => return Err(format!("unexpected argument {}", arg).into()),
+        }
+    }
+
+    let target = target.ok_or("usage: recover (--to-seq N | --to-time T) [--dry-run]")?;
+
+    recovery::run(config, target, dry_run)
+}
+
+// options.txt of the data, the followers ask the leader for its options.
+// the data can't be loaded from the files without the options,
+// but e.g. a snapshot can, so the errors are only printed here
+fn read_options(config: &Config) -> Option<options::Options> {
+    let res = match &config.leader {
+        Some(leader) => replication::options(leader, config.leader_api_key.as_deref())
+            .map_err(|e| format!("leader {}: {}", leader, e)),
+        None => options::Options::read(&load::options_path(
+            &config.data_path,
+            config.options_path.as_deref(),
+        )),
+    };
+
+    match res {
+        Ok(options) => {
+            info!("options: timestamp {}, {:?} mode", options.timestamp, options.mode);
+            Some(options)
+        }
+        Err(e) => {
+            warn!("options: {}", e);
+            None
+        }
+    }
+}
+
+// writes the storage to the zip archive and options.txt next to it
+fn run_export(storage: &dyn storage::Storage, mode: options::RunMode, args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut out = None;
+    let mut per_file = export::DEFAULT_PER_FILE;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--per-file" => {
+                per_file = args
+                    .next()
+                    .ok_or("--per-file needs a value")?
+                    .parse()
+                    .map_err(|_| "invalid --per-file value")?;
+            }
+            path if out.is_none() => out = Some(path),
+            arg => return Err(format!("unexpected argument {}", arg).into()),
+        }
+    }
+
+    let out = Path::new(out.ok_or("usage: export <out.zip> [--per-file N]")?);
+
+    let w = export::write_zip(storage, BufWriter::new(File::create(out)?), per_file, mode)?;
+    w.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+    let options = out.with_file_name("options.txt");
+    fs::write(&options, export::options(storage, mode))?;
+
+    println!("exported to {} and {}", out.display(), options.display());
+
+    Ok(())
+}
+
+// the storage with the report of the data load, if the data files were loaded
+type InitStorage = (Box<dyn storage::Storage>, Option<load::Report>);
+
+// the storage with the log replayed to it
+type OpenData = (Box<dyn storage::Storage>, Option<load::Report>, Option<wal::Wal>);
+
+// opens the storage with the data of the leader or the configured one
+fn open_data(
+    config: &Config,
+    options: Option<&options::Options>,
+    progress: &load::Progress,
+) -> Result<OpenData, Box<dyn Error>> {
+    let (mut storage, load_report): InitStorage = match &config.leader {
+        Some(leader) => {
+            progress.set_phase("leader");
+            info!("follower of {}, loading its snapshot", leader);
+            (Box::new(replication::bootstrap(leader, config.leader_api_key.as_deref())), None)
+        }
+        None => init_storage(config, options, progress)?,
+    };
+
+    // the follower's data comes from the leader, so it has no log of its own
+    progress.set_phase("log");
+    let wal = open_wal(config, &mut *storage).map_err(|e| format!("WAL error: {}", e))?;
+
+    Ok((storage, load_report, wal))
+}
+
+// reads the data without modifying any files, so it's safe next to the running
+// server: the newest snapshot or the data files are loaded in memory and the log
+// is replayed without truncating its tail. the files of the memory-mapped storage
+// aren't opened, the data files with the log have the same data
+fn read_data(config: &Config, options: Option<&options::Options>) -> Result<Box<dyn storage::Storage>, Box<dyn Error>> {
+    if let Some(leader) = &config.leader {
+        info!("follower of {}, loading its snapshot", leader);
+        return Ok(Box::new(replication::bootstrap(leader, config.leader_api_key.as_deref())));
+    }
+
+    let snapshot = match (&config.snapshot_dir, config.storage) {
+        (Some(dir), StorageKind::Memory) => storage::snapshot::load_latest(dir, u64::MAX)?,
+        _ => None,
+    };
+    let mut storage = match snapshot {
+        Some((storage, path)) => {
+            info!("loaded snapshot {}", path.display());
+            storage
+        }
+        None => {
+            let mut storage = storage::MemStorage::new();
+            load_data(config, options, &load::Progress::default(), &mut storage)?;
+            storage
+        }
+    };
+
+    if let Some(path) = &config.wal_path {
+        let records = match wal::read(path) {
+            Ok(records) => records,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(format!("WAL error: {}", e).into()),
+        };
+
+        let mut replayed = 0;
+        for (seq, mutation) in wal::replay_after(&records, storage.last_seq())? {
+            if mutation.apply(&mut storage).is_ok() {
+                replayed += 1;
+            }
+            storage.set_last_seq(seq);
+        }
+        info!("wal {}: {} records, replayed {}", path.display(), records.len(), replayed);
+    }
+
+    Ok(Box::new(storage))
+}
+
+// opens the configured storage backend and fills it from the data files,
+// the memory-mapped storage is filled only once
+fn init_storage(
+    config: &Config,
+    options: Option<&options::Options>,
+    progress: &load::Progress,
+) -> Result<InitStorage, Box<dyn Error>> {
+    match config.storage {
+        StorageKind::Memory => {
+            if let Some(dir) = &config.snapshot_dir {
+                progress.set_phase("snapshot");
+                if let Some((storage, path)) = storage::snapshot::load_latest(dir, u64::MAX)? {
+                    info!("loaded snapshot {}", path.display());
+                    return Ok((Box::new(storage), None));
+                }
+            }
+
+            let mut storage = storage::MemStorage::new();
+
+            info!("loading data to in-memory storage");
+            let report = load_data(config, options, progress, &mut storage)?;
+
+            Ok((Box::new(storage), Some(report)))
+        }
+        StorageKind::Mmap => {
+            let mut storage = storage::MmapStorage::open(&config.mmap_dir)?;
+
+            let mut report = None;
+
+            if storage.is_complete() {
+                info!("opened memory-mapped storage {}", config.mmap_dir.display());
+            } else {
+                info!("loading data to memory-mapped storage {}", config.mmap_dir.display());
+                report = Some(load_data(config, options, progress, &mut storage)?);
+                storage.mark_complete()?;
+            }
+
+            Ok((Box::new(storage), report))
+        }
+    }
+}
+
+// loads the data files and prints the report,
+// fails in the strict mode if any entity is rejected
+fn load_data(
+    config: &Config,
+    options: Option<&options::Options>,
+    progress: &load::Progress,
+    storage: &mut dyn storage::Storage,
+) -> Result<load::Report, Box<dyn Error>> {
+    let options = options.ok_or("the data can't be loaded without its options")?;
+
+    let report = load::run(storage, &config.data_path, options, progress)?;
+    info!("{}", report);
+
+    if config.strict_load && !report.is_clean() {
+        return Err("strict load: the data has rejected entities".into());
+    }
+
+    Ok(report)
+}
+
+// opens the write-ahead log if it's enabled
+// and replays the mutations the storage doesn't have yet
+fn open_wal(config: &Config, storage: &mut dyn storage::Storage) -> Result<Option<wal::Wal>, Box<dyn Error>> {
+    let path = match &config.wal_path {
+        Some(path) if config.leader.is_none() => path,
+        _ => return Ok(None),
+    };
+
+    let (mut wal, records) = wal::Wal::open(path, config.wal_fsync)?;
+
+    let mut replayed = 0;
+    let mut rejected = 0;
+
+    for (seq, mutation) in wal::replay_after(&records, storage.last_seq())? {
+        match mutation.apply(storage) {
+            Ok(_) => replayed += 1,
+            Err(_) => rejected += 1,
+        }
+        storage.set_last_seq(seq);
+    }
+    storage.flush()?;
+
+    // the storage may be ahead of the log, e.g. a snapshot taken without the log
+    wal.skip_to(storage.last_seq());
+
+    info!(
+        "wal {}: {} records, replayed {}, rejected {}",
+        path.display(),
+        records.len(),
+        replayed,
+        rejected
+    );
+
+    Ok(Some(wal))
+}
@@ -0,0 +1,229 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+
+use actix_web::{dev::ServiceResponse, http::Method};
+
+use crate::state::AppState;
+
+// upper bounds of the request latency buckets in seconds
+const REQUEST_BUCKETS: [f64; 12] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0,
+];
+
+// the storage lock is usually taken at once, the waits are for the writers
+const LOCK_BUCKETS: [f64; 10] = [
+    0.000001, 0.00001, 0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 1.0,
+];
+
+// the requests which match no route are counted together
+const UNMATCHED: &str = "unmatched";
+
+// counters of the server, rendered by GET /metrics in the prometheus text format.
+// the gauges of the data are read from the storage when rendered
+pub struct Metrics {
+    // latencies by the method, the route pattern and the status code
+    requests: RwLock<HashMap<(Method, String, u16), Arc<Histogram>>>,
+    lock_read: Histogram,
+    lock_write: Histogram,
+}
+
+pub enum Lock {
+    Read,
+    Write,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            requests: RwLock::new(HashMap::new()),
+            lock_read: Histogram::new(&LOCK_BUCKETS),
+            lock_write: Histogram::new(&LOCK_BUCKETS),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    // counts the served request, called by the middleware of the app
+    pub fn request<B>(&self, res: &ServiceResponse<B>, elapsed: Duration) {
+        let route = res
+            .request()
+            .match_pattern()
+            .unwrap_or_else(|| UNMATCHED.to_string());
+        let key = (res.request().method().clone(), route, res.status().as_u16());
+
+        let histogram = self.requests.read().unwrap().get(&key).cloned();
+        let histogram = match histogram {
+            Some(histogram) => histogram,
+            None => self
+                .requests
+                .write()
+                .unwrap()
+                .entry(key)
+                .or_insert_with(|| Arc::new(Histogram::new(&REQUEST_BUCKETS)))
+                .clone(),
+        };
+
+        histogram.observe(elapsed);
+    }
+
+    pub fn lock_wait(&self, lock: Lock, elapsed: Duration) {
+        match lock {
+            Lock::Read => self.lock_read.observe(elapsed),
+            Lock::Write => self.lock_write.observe(elapsed),
+        }
+    }
+
+    pub fn render(&self, data: &AppState) -> String {
+        let mut out = String::new();
+
+        let mut requests: Vec<_> = self
+            .requests
+            .read()
+            .unwrap()
+            .iter()
+            .map(|((method, route, code), h)| {
+                let labels = format!(
+                    "method=\"{}\",route=\"{}\",code=\"{}\"",
+                    method,
+                    escape(route),
+                    code
+                );
+                (labels, h.clone())
+            })
+            .collect();
+        requests.sort_by(|a, b| a.0.cmp(&b.0));
+
+        header(&mut out, "hlcup_http_requests_total", "counter", "served requests");
+        for (labels, h) in &requests {
+            let _ = writeln!(out, "hlcup_http_requests_total{{{}}} {}", labels, h.count());
+        }
+
+        header(
+            &mut out,
+            "hlcup_http_request_duration_seconds",
+            "histogram",
+            "time to the response headers",
+        );
+        for (labels, h) in &requests {
+            h.write(&mut out, "hlcup_http_request_duration_seconds", labels);
+        }
+
+        header(
+            &mut out,
+            "hlcup_storage_lock_wait_seconds",
+            "histogram",
+            "time waited for the storage lock",
+        );
+        self.lock_read
+            .write(&mut out, "hlcup_storage_lock_wait_seconds", "mode=\"read\"");
+        self.lock_write
+            .write(&mut out, "hlcup_storage_lock_wait_seconds", "mode=\"write\"");
+
+        let (counts, dicts, last_seq) = {
+            let s = data.read_storage();
+            (s.counts(), s.dict_sizes(), s.last_seq())
+        };
+
+        header(&mut out, "hlcup_entities", "gauge", "stored entities");
+        for (kind, count) in [
+            ("users", counts.users),
+            ("locations", counts.locations),
+            ("visits", counts.visits),
+        ] {
+            let _ = writeln!(out, "hlcup_entities{{kind=\"{}\"}} {}", kind, count);
+        }
+
+        header(&mut out, "hlcup_dict_entries", "gauge", "entries of the string dictionaries");
+        for (dict, size) in dicts {
+            let _ = writeln!(out, "hlcup_dict_entries{{dict=\"{}\"}} {}", dict, size);
+        }
+
+        header(&mut out, "hlcup_last_seq", "gauge", "sequence number of the last mutation");
+        let _ = writeln!(out, "hlcup_last_seq {}", last_seq);
+
+        // the data loaded from a snapshot or the leader has no load report
+        if let Some(report) = &*data.load_report.lock().unwrap() {
+            header(
+                &mut out,
+                "hlcup_load_duration_seconds",
+                "gauge",
+                "duration of the last load of the data files",
+            );
+            let _ = writeln!(out, "hlcup_load_duration_seconds {}", report.seconds);
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+// the route patterns may have any characters of the paths
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+// the bucket counters aren't cumulative, they are summed up when rendered
+struct Histogram {
+    bounds: &'static [f64],
+    // the last one is for the values above the bounds
+    buckets: Vec<AtomicU64>,
+    sum_ns: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum_ns: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let idx = self
+            .bounds
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(self.bounds.len());
+
+        self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        self.sum_ns
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn count(&self) -> u64 {
+        self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).sum()
+    }
+
+    fn write(&self, out: &mut String, name: &str, labels: &str) {
+        let mut count = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+            let le = match self.bounds.get(i) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, le, count);
+        }
+
+        let sum = self.sum_ns.load(Ordering::Relaxed) as f64 / 1e9;
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+    }
+}
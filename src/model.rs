@@ -18,12 +18,29 @@ impl From<&str> for Gender {
     }
 }
 
-impl ToString for Gender {
-    fn to_string(&self) -> String {
+impl Gender {
+    // compact code for the binary storage formats
+    pub fn code(&self) -> u8 {
         match self {
-            Gender::Male => String::from("m"),
-            Gender::Female => String::from("f"),
-            _ => String::from(""),
+            Gender::None => 0,
+            Gender::Male => 1,
+            Gender::Female => 2,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            1 => Gender::Male,
+            2 => Gender::Female,
+            _ => Gender::None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Gender::Male => "m",
+            Gender::Female => "f",
+            _ => "",
         }
     }
 }
@@ -112,33 +129,56 @@ pub struct LocationsDataJSON {
     pub locations: Vec<LocationJSON>,
 }
 
+// borrowed views of the entities for serialization straight from the storage,
+// strings point into the storage dictionaries instead of being cloned
+#[derive(Serialize, Debug)]
+pub struct UserRef<'a> {
+    pub id: u32,
+    pub email: &'a str,
+    pub first_name: &'a str,
+    pub last_name: &'a str,
+    pub gender: &'a str,
+    pub birth_date: i32,
+}
+
+#[derive(Serialize, Debug)]
+pub struct LocationRef<'a> {
+    pub id: u32,
+    pub distance: u32,
+    pub city: &'a str,
+    pub country: &'a str,
+    pub place: &'a str,
+}
+
 #[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct UserVisitsParams {
-    pub fromDate: Option<i32>,
-    pub toDate: Option<i32>,
+    pub from_date: Option<i32>,
+    pub to_date: Option<i32>,
     pub country: Option<String>,
-    pub toDistance: Option<u32>,
+    pub to_distance: Option<u32>,
 }
 
 #[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct LocationAvgParams {
-    pub fromDate: Option<i32>,
-    pub toDate: Option<i32>,
-    pub fromAge: Option<u32>,
-    pub toAge: Option<u32>,
+    pub from_date: Option<i32>,
+    pub to_date: Option<i32>,
+    pub from_age: Option<u32>,
+    pub to_age: Option<u32>,
     pub gender: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
-pub struct UserVisitJSON {
+pub struct UserVisitRef<'a> {
     pub mark: u8,
     pub visited_at: i32,
-    pub place: String,
+    pub place: &'a str,
 }
 
 #[derive(Debug, Serialize)]
-pub struct UserVisitsJSON {
-    pub visits: Vec<UserVisitJSON>,
+pub struct UserVisitsRef<'a> {
+    pub visits: Vec<UserVisitRef<'a>>,
 }
 
 #[derive(Debug, Serialize)]
@@ -0,0 +1,80 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    model,
+    storage::{StoreError, Storage},
+};
+
+// change of the data accepted by the server,
+// it's what gets written to the log and replayed from it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", content = "entity", rename_all = "snake_case")]
+pub enum Mutation {
+    NewUser(model::UserJSON),
+    NewLocation(model::LocationJSON),
+    NewVisit(model::VisitJSON),
+}
+
+impl Mutation {
+    // name of the mutation, same as its "type" in json
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Mutation::NewUser(_) => "new_user",
+            Mutation::NewLocation(_) => "new_location",
+            Mutation::NewVisit(_) => "new_visit",
+        }
+    }
+
+    // id of the new entity
+    pub fn id(&self) -> u32 {
+        match self {
+            Mutation::NewUser(user) => user.id,
+            Mutation::NewLocation(location) => location.id,
+            Mutation::NewVisit(visit) => visit.id,
+        }
+    }
+
+    // the checks of the storage which apply would fail on,
+    // so the mutation is logged only if it's going to be applied
+    pub fn check(&self, s: &dyn Storage) -> Result<(), StoreError> {
+        match self {
+            Mutation::NewUser(user) => {
+                if s.user(user.id).is_some() {
+                    return Err(StoreError::UserExists);
+                }
+                if s.has_email(&user.email) {
+                    return Err(StoreError::EmailExists);
+                }
+                if matches!(model::Gender::from(user.gender.as_str()), model::Gender::None) {
+                    return Err(StoreError::UnknownGender);
+                }
+            }
+            Mutation::NewLocation(location) => {
+                if s.location(location.id).is_some() {
+                    return Err(StoreError::LocationExists);
+                }
+            }
+            Mutation::NewVisit(visit) => {
+                if s.visit(visit.id).is_some() {
+                    return Err(StoreError::VisitExists);
+                }
+                if s.user(visit.user).is_none() {
+                    return Err(StoreError::UserNotFound);
+                }
+                if s.location(visit.location).is_none() {
+                    return Err(StoreError::LocationNotFound);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn apply(&self, s: &mut dyn Storage) -> Result<(), StoreError> {
+        match self {
+            Mutation::NewUser(user) => s.store_user(user),
+            Mutation::NewLocation(location) => s.store_location(location),
+            Mutation::NewVisit(visit) => s.store_visit(visit),
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use std::{fmt, fs, path::Path};
+
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+
+// options.txt of the dataset: the first line is the current time of the data,
+// the second one is the run mode of the contest
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Options {
+    pub timestamp: i64,
+    pub mode: RunMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunMode {
+    // 0, the small dataset of the test runs
+    Test,
+    // 1, the full dataset under the rating load
+    Rating,
+}
+
+impl Options {
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let s = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Self::parse(&s).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    // the mode line may be missing, e.g. in the files exported
+    // before it was written, such data is of the test mode
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut lines = s.lines().map(str::trim);
+
+        // the ages of the users are counted from the timestamp,
+        // so it has to be a valid date
+        let timestamp = match lines.next() {
+            Some(line) if !line.is_empty() => line
+                .parse()
+                .ok()
+                .filter(|ts| NaiveDateTime::from_timestamp_opt(*ts, 0).is_some())
+                .ok_or_else(|| format!("invalid timestamp {:?} in the first line", line))?,
+            _ => return Err("no timestamp in the first line".to_string()),
+        };
+
+        let mode = match lines.next() {
+            None | Some("") | Some("0") => RunMode::Test,
+            Some("1") => RunMode::Rating,
+            Some(line) => return Err(format!("invalid run mode {:?} in the second line, 0 or 1 expected", line)),
+        };
+
+        Ok(Options { timestamp, mode })
+    }
+}
+
+// the contents of options.txt
+impl fmt::Display for Options {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = match self.mode {
+            RunMode::Test => 0,
+            RunMode::Rating => 1,
+        };
+        writeln!(f, "{}", self.timestamp)?;
+        writeln!(f, "{}", mode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_lines() {
+        let options = Options::parse("1503695452\n1\n").unwrap();
+        assert_eq!(options.timestamp, 1503695452);
+        assert_eq!(options.mode, RunMode::Rating);
+
+        assert_eq!(Options::parse(" 1503695452 ").unwrap().mode, RunMode::Test);
+        assert_eq!(Options::parse("-1\n0").unwrap().timestamp, -1);
+
+        let options = Options { timestamp: 1, mode: RunMode::Rating };
+        assert_eq!(Options::parse(&options.to_string()).unwrap().mode, RunMode::Rating);
+    }
+
+    #[test]
+    fn rejects_invalid_lines() {
+        assert_eq!(Options::parse("").unwrap_err(), "no timestamp in the first line");
+        assert!(Options::parse("now\n0").unwrap_err().contains("first line"));
+        assert!(Options::parse("1\n2").unwrap_err().contains("second line"));
+
+        // an i64 beyond the dates of chrono
+        let err = Options::parse(&format!("{}\n0", i64::MAX)).unwrap_err();
+        assert!(err.contains("invalid timestamp"));
+        assert!(Options::parse("-9000000000000000").is_err());
+    }
+}
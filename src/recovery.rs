@@ -0,0 +1,200 @@
+use std::{
+    error::Error,
+    fs,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use chrono::{DateTime, NaiveDateTime};
+
+use crate::{
+    config::{Config, StorageKind},
+    load,
+    mutation::Mutation,
+    options::Options,
+    storage::{snapshot, MemStorage, Storage},
+    wal,
+};
+
+// point of the log the data is restored to
+pub enum Target {
+    // sequence number of the last mutation to keep
+    Seq(u64),
+    // unix time in milliseconds, the mutations accepted after it are dropped
+    Time(i64),
+}
+
+// parses the target time given as unix seconds or in rfc 3339,
+// returns unix time in milliseconds
+pub fn parse_time(s: &str) -> Result<i64, String> {
+    if let Ok(secs) = s.parse::<i64>() {
+        return secs.checked_mul(1000).ok_or_else(|| format!("invalid time {}", s));
+    }
+    DateTime::parse_from_rfc3339(s)
+        .map(|t| t.timestamp_millis())
+        .map_err(|_| format!("invalid time {}", s))
+}
+
+// restores the in-memory storage as of the target: loads the newest snapshot
+// before it and replays the log up to it. unless it's a dry run, the newer
+// snapshots are set aside, the log is cut at the target (the full log is kept
+// in a backup file) and the restored data is saved as a new snapshot,
+// so the next start serves the restored data
+pub fn run(config: &Config, target: Target, dry_run: bool) -> Result<(), Box<dyn Error>> {
+    if config.storage != StorageKind::Memory {
+        return Err("recovery is supported for the in-memory storage only".into());
+    }
+    let wal_path = config
+        .wal_path
+        .as_ref()
+        .ok_or("recovery needs the write-ahead log (HLCUP_WAL)")?;
+    let dir = config
+        .snapshot_dir
+        .as_ref()
+        .ok_or("recovery needs the snapshots directory (HLCUP_SNAPSHOT_DIR)")?;
+
+    let mut records = wal::read(wal_path)?;
+    let last_seq = records.last().map(|r| r.seq).unwrap_or(0);
+
+    let target_seq = match target {
+        Target::Seq(seq) if seq > last_seq => {
+            return Err(format!("the log ends at {}, can't recover to {}", last_seq, seq).into())
+        }
+        Target::Seq(seq) => seq,
+        Target::Time(time) => records
+            .iter()
+            .take_while(|r| r.time <= time)
+            .last()
+            .map(|r| r.seq)
+            .unwrap_or(0),
+    };
+
+    let dropped = records.iter().filter(|r| r.seq > target_seq).count();
+    records.retain(|r| r.seq <= target_seq);
+
+    let (mut storage, source) = match snapshot::load_latest(dir, target_seq)? {
+        Some((storage, path)) => (storage, path.display().to_string()),
+        None => {
+            let options = Options::read(&load::options_path(
+                &config.data_path,
+                config.options_path.as_deref(),
+            ))?;
+            let mut storage = MemStorage::new();
+            load::run(&mut storage, &config.data_path, &options, &load::Progress::default())?;
+            (storage, "data archive".to_string())
+        }
+    };
+
+    let base_seq = storage.last_seq();
+    let replay = wal::replay_after(&records, base_seq)?;
+
+    if let Some((first, _)) = replay.first() {
+        if *first != base_seq + 1 {
+            return Err(format!(
+                "the log starts at {}, records after {} are missing",
+                first, base_seq
+            )
+            .into());
+        }
+    }
+
+    let mut users = 0;
+    let mut locations = 0;
+    let mut visits = 0;
+    let mut rejected = 0;
+
+    for (seq, mutation) in &replay {
+        match mutation {
+            Mutation::NewUser(_) => users += 1,
+            Mutation::NewLocation(_) => locations += 1,
+            Mutation::NewVisit(_) => visits += 1,
+        }
+        if mutation.apply(&mut storage).is_err() {
+            rejected += 1;
+        }
+        storage.set_last_seq(*seq);
+    }
+    storage.set_last_seq(target_seq);
+
+    let target_time = records.last().map(|r| format_time(r.time));
+    let counts = storage.counts();
+
+    println!(
+        "recovery target: seq {}{}",
+        target_seq,
+        target_time.map(|t| format!(" ({})", t)).unwrap_or_default()
+    );
+    println!("starting from: {} (seq {})", source, base_seq);
+    println!(
+        "replayed {} records: new users {}, new locations {}, new visits {}, rejected {}",
+        replay.len(),
+        users,
+        locations,
+        visits,
+        rejected
+    );
+    println!("dropped {} records after the target", dropped);
+    println!(
+        "restored: users {}, visits {}, locations {}",
+        counts.users, counts.visits, counts.locations
+    );
+
+    let newer: Vec<PathBuf> = snapshot::list(dir)?
+        .into_iter()
+        .filter(|(seq, _)| *seq > target_seq)
+        .map(|(_, path)| path)
+        .collect();
+    for path in &newer {
+        println!("newer snapshot set aside: {}", path.display());
+    }
+
+    if dry_run {
+        println!("dry run, nothing is changed");
+        return Ok(());
+    }
+
+    // newer snapshots are set aside first: if the recovery is interrupted
+    // after that, the next start loads an older snapshot and the full log,
+    // and the recovery can be run again
+    for path in &newer {
+        let mut aside = path.clone().into_os_string();
+        aside.push(".discarded");
+        fs::rename(path, aside)?;
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let mut backup = wal_path.clone().into_os_string();
+    backup.push(format!(".{}.bak", now));
+    fs::copy(wal_path, &backup)?;
+    println!("full log is kept in {}", PathBuf::from(backup).display());
+
+    wal::truncate_after(wal_path, target_seq)?;
+
+    let path = snapshot::save(&storage, dir, config.snapshot_keep)?;
+    println!("restored snapshot written: {}", path.display());
+
+    Ok(())
+}
+
+fn format_time(ms: i64) -> String {
+    NaiveDateTime::from_timestamp_millis(ms)
+        .map(|t| t.format("%Y-%m-%d %H:%M:%S%.3f UTC").to_string())
+        .unwrap_or_else(|| ms.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_target_time() {
+        assert_eq!(parse_time("1503695452"), Ok(1_503_695_452_000));
+        assert_eq!(parse_time("-1"), Ok(-1000));
+        assert_eq!(parse_time("2017-08-25T21:10:52.5Z"), Ok(1_503_695_452_500));
+        assert_eq!(parse_time("2017-08-26T00:10:52+03:00"), Ok(1_503_695_452_000));
+
+        assert!(parse_time(&i64::MAX.to_string()).is_err());
+        assert!(parse_time("2017-08-25").is_err());
+        assert!(parse_time("").is_err());
+    }
+}
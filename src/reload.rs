@@ -0,0 +1,223 @@
+use std::{
+    error::Error,
+    fmt,
+    path::{Path, PathBuf},
+    thread,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::web;
+use serde::Serialize;
+
+use crate::{
+    config::StorageKind,
+    load,
+    options::Options,
+    state::AppState,
+    storage::MemStorage,
+};
+
+// state of the reload of the data, see start
+#[derive(Serialize, Clone)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum Status {
+    Idle,
+    Running {
+        data: String,
+        // unix time in seconds
+        started: u64,
+    },
+    Done {
+        data: String,
+        // mutations committed during the load and applied to the new data
+        replayed: usize,
+        rejected: usize,
+        seconds: f64,
+    },
+    Failed {
+        data: String,
+        error: String,
+    },
+}
+
+#[derive(Debug)]
+pub enum StartError {
+    // only the in-memory storage can be reloaded
+    Unsupported,
+    // the followers get their data from the leader
+    ReadOnly,
+    Running,
+    Options(String),
+    // with the log the new data has to be saved as a snapshot,
+    // otherwise a restart replays its mutations to the configured data
+    NotDurable,
+    // the path isn't in the reload_dirs
+    PathNotAllowed(PathBuf),
+}
+
+impl fmt::Display for StartError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StartError::Unsupported => f.write_str("reload is supported only by the in-memory storage"),
+            StartError::ReadOnly => f.write_str("storage is read-only"),
+            StartError::Running => f.write_str("reload is already running"),
+            StartError::Options(e) => write!(f, "options: {}", e),
+            StartError::NotDurable => {
+                f.write_str("reload with the write-ahead log requires the snapshots directory")
+            }
+            StartError::PathNotAllowed(path) => {
+                write!(f, "{} is not in the reload directories", path.display())
+            }
+        }
+    }
+}
+
+// loads the data from the path into a new storage in the background and
+// swaps it with the current one once it's loaded, the server keeps serving
+// the current data meanwhile, so both are in the memory during the load.
+// with replay the mutations committed during the load are applied
+// to the new data before the swap, otherwise they are lost
+pub fn start(
+    data: web::Data<AppState>,
+    path: PathBuf,
+    options_path: Option<PathBuf>,
+    replay: bool,
+) -> Result<(), StartError> {
+    if data.config.storage != StorageKind::Memory {
+        return Err(StartError::Unsupported);
+    }
+    if data.config.leader.is_some() {
+        return Err(StartError::ReadOnly);
+    }
+    if data.config.wal_path.is_some() && data.config.snapshot_dir.is_none() {
+        return Err(StartError::NotDurable);
+    }
+
+    if path != data.config.data_path && !is_allowed(&data.config.reload_dirs, &path) {
+        return Err(StartError::PathNotAllowed(path));
+    }
+    if let Some(options_path) = &options_path {
+        if data.config.options_path.as_ref() != Some(options_path)
+            && !is_allowed(&data.config.reload_dirs, options_path)
+        {
+            return Err(StartError::PathNotAllowed(options_path.clone()));
+        }
+    }
+
+    let options = Options::read(&load::options_path(&path, options_path.as_deref()))
+        .map_err(StartError::Options)?;
+
+    let mut status = data.reload.lock().unwrap();
+    if let Status::Running { .. } = *status {
+        return Err(StartError::Running);
+    }
+
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    *status = Status::Running {
+        data: path.display().to_string(),
+        started,
+    };
+    drop(status);
+
+    if replay {
+        data.start_capture();
+    }
+
+    thread::spawn(move || {
+        let start = Instant::now();
+
+        info!("reload: loading {}", path.display());
+
+        let status = match reload(&data, &path, options) {
+            Ok((replayed, rejected)) => {
+                info!(
+                    "reload: {} swapped in {:?}, replayed {}, rejected {}",
+                    path.display(),
+                    start.elapsed(),
+                    replayed,
+                    rejected
+                );
+                Status::Done {
+                    data: path.display().to_string(),
+                    replayed,
+                    rejected,
+                    seconds: start.elapsed().as_secs_f64(),
+                }
+            }
+            Err(e) => {
+                error!("reload: {}: {}", path.display(), e);
+                data.stop_capture();
+                Status::Failed {
+                    data: path.display().to_string(),
+                    error: e.to_string(),
+                }
+            }
+        };
+
+        *data.reload.lock().unwrap() = status;
+    });
+
+    Ok(())
+}
+
+fn reload(data: &AppState, path: &Path, options: Options) -> Result<(usize, usize), Box<dyn Error>> {
+    let mut storage = MemStorage::new();
+
+    let report = load::run(&mut storage, path, &options, &load::Progress::default())?;
+    info!("{}", report);
+
+    if data.config.strict_load && !report.is_clean() {
+        return Err("strict load: the data has rejected entities".into());
+    }
+
+    // with the snapshots the new data is saved before the swap,
+    // so a restart loads it together with the log after it
+    let (replayed, rejected) = data.swap_storage(Box::new(storage))?;
+
+    *data.load_report.lock().unwrap() = Some(report);
+    *data.options.lock().unwrap() = Some(options);
+
+    Ok((replayed, rejected))
+}
+
+// whether the path is in one of the directories, the links and
+// the .. components are resolved first, so they can't lead out of them
+fn is_allowed(dirs: &[PathBuf], path: &Path) -> bool {
+    let path = match path.canonicalize() {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+
+    dirs.iter()
+        .filter_map(|dir| dir.canonicalize().ok())
+        .any(|dir| path.starts_with(dir))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn allows_only_the_paths_in_the_dirs() {
+        let root = std::env::temp_dir().join(format!("hlcup-reload-{}", std::process::id()));
+        let data = root.join("data");
+        fs::create_dir_all(&data).unwrap();
+        fs::write(data.join("data.zip"), b"").unwrap();
+        fs::write(root.join("secret"), b"").unwrap();
+
+        let dirs = vec![data.clone()];
+        assert!(is_allowed(&dirs, &data.join("data.zip")));
+        assert!(is_allowed(&dirs, &data));
+        assert!(!is_allowed(&dirs, &data.join("../secret")));
+        assert!(!is_allowed(&dirs, &root.join("secret")));
+        assert!(!is_allowed(&dirs, &data.join("missing.zip")));
+        assert!(!is_allowed(&[], &data.join("data.zip")));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
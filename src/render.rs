@@ -0,0 +1,43 @@
+use std::cell::RefCell;
+
+use bytes::Bytes;
+use serde::Serialize;
+
+use crate::storage::Storage;
+
+// initial size of the per-thread buffer, enough for most of the responses
+const BUF_CAPACITY: usize = 64 * 1024;
+
+thread_local! {
+    // reusable per-worker buffer for the response bodies
+    static BUF: RefCell<Vec<u8>> = RefCell::new(Vec::with_capacity(BUF_CAPACITY));
+}
+
+// serializes the value to json into the per-thread buffer
+// and copies the written bytes out as a response body,
+// so the only allocation per response is the body itself.
+// (splitting a BytesMut and reclaiming it on the next call
+// was measured to be slower than the single copy)
+pub fn to_bytes<T: Serialize>(value: &T) -> Bytes {
+    BUF.with(|buf| {
+        let mut buf = buf.borrow_mut();
+        buf.clear();
+
+        // writing to Vec can't fail and all the models serialize infallibly
+        serde_json::to_writer(&mut *buf, value).unwrap();
+
+        Bytes::copy_from_slice(&buf)
+    })
+}
+
+pub fn user(s: &dyn Storage, id: u32) -> Option<Bytes> {
+    s.user(id).map(|user| to_bytes(&user))
+}
+
+pub fn visit(s: &dyn Storage, id: u32) -> Option<Bytes> {
+    s.visit(id).map(|visit| to_bytes(&visit))
+}
+
+pub fn location(s: &dyn Storage, id: u32) -> Option<Bytes> {
+    s.location(id).map(|location| to_bytes(&location))
+}
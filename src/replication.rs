@@ -0,0 +1,217 @@
+use std::{
+    error::Error,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    thread,
+    time::Duration,
+};
+
+use actix_web::web;
+
+use crate::{
+    mutation::Mutation,
+    options::Options,
+    state::AppState,
+    storage::{snapshot, MemStorage, Storage},
+};
+
+// pause before reconnecting to the leader
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+// downloads the snapshot of the leader's storage,
+// retries until the leader is up
+pub fn bootstrap(leader: &str, key: Option<&str>) -> MemStorage {
+    loop {
+        match fetch_snapshot(leader, key) {
+            Ok(storage) => return storage,
+            Err(e) => warn!("replication: snapshot of {}: {}", leader, e),
+        }
+        thread::sleep(RETRY_INTERVAL);
+    }
+}
+
+// applies the leader's stream of mutations to the follower's storage,
+// reconnects from the last applied sequence number. never returns
+pub fn follow(data: web::Data<AppState>, leader: String) {
+    loop {
+        if let Err(e) = tail(&data, &leader) {
+            warn!("replication: {}", e);
+            thread::sleep(RETRY_INTERVAL);
+        }
+    }
+}
+
+// options of the leader's data, the follower runs in the same mode
+pub fn options(leader: &str, key: Option<&str>) -> Result<Options, Box<dyn Error>> {
+    let body = get(leader, "/admin/options", key, None)?;
+    Ok(serde_json::from_reader(body)?)
+}
+
+fn fetch_snapshot(leader: &str, key: Option<&str>) -> Result<MemStorage, Box<dyn Error>> {
+    let mut body = get(leader, "/replication/snapshot", key, None)?;
+    Ok(snapshot::read(&mut body)?)
+}
+
+// reads the server-sent events of the leader until the stream ends.
+// returns Ok when the follower has resynced and needs to reconnect
+fn tail(data: &AppState, leader: &str) -> Result<(), Box<dyn Error>> {
+    let mut last_seq = data.read_storage().last_seq();
+
+    let key = data.config.leader_api_key.as_deref();
+    let mut r = BufReader::new(get(leader, "/events", key, Some(last_seq))?);
+    info!("replication: following {} from {}", leader, last_seq);
+
+    let mut line = String::new();
+    let mut id = None;
+    let mut event = String::new();
+    let mut payload = String::new();
+
+    loop {
+        line.clear();
+        if r.read_line(&mut line)? == 0 {
+            return Err("the leader closed the stream".into());
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+
+        if let Some(v) = line.strip_prefix("id:") {
+            id = Some(v.trim().parse::<u64>()?);
+        } else if let Some(v) = line.strip_prefix("event:") {
+            event = v.trim().to_string();
+        } else if let Some(v) = line.strip_prefix("data:") {
+            payload.push_str(v.trim_start());
+        } else if line.is_empty() && !event.is_empty() {
+            if event == "reset" {
+                // the leader doesn't have the missed mutations anymore
+                resync(data, leader)?;
+                return Ok(());
+            }
+
+            let seq = id.take().ok_or("event without id")?;
+            if seq > last_seq + 1 {
+                warn!("replication: missed mutations {}..{}", last_seq + 1, seq - 1);
+                resync(data, leader)?;
+                return Ok(());
+            }
+
+            if seq == last_seq + 1 {
+                let mutation: Mutation = serde_json::from_str(&payload)?;
+                debug!("replication: mutation {} {}", seq, mutation.kind());
+                data.replicate(seq, &mutation);
+                last_seq = seq;
+            }
+
+            event.clear();
+            payload.clear();
+        }
+    }
+}
+
+fn resync(data: &AppState, leader: &str) -> Result<(), Box<dyn Error>> {
+    let storage = fetch_snapshot(leader, data.config.leader_api_key.as_deref())?;
+    info!("replication: resynced from the snapshot at {}", storage.last_seq());
+    data.replace_storage(Box::new(storage));
+    Ok(())
+}
+
+// minimal blocking http/1.1 GET, returns the body of the 200 response
+fn get(addr: &str, path: &str, key: Option<&str>, last_event_id: Option<u64>) -> io::Result<Box<dyn Read>> {
+    let mut stream = TcpStream::connect(addr)?;
+
+    let mut req = format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n", path, addr);
+    if let Some(key) = key {
+        req.push_str(&format!("Authorization: Bearer {}\r\n", key));
+    }
+    if let Some(id) = last_event_id {
+        req.push_str(&format!("Last-Event-ID: {}\r\n", id));
+    }
+    req.push_str("\r\n");
+    stream.write_all(req.as_bytes())?;
+
+    let mut r = BufReader::new(stream);
+
+    let mut line = String::new();
+    r.read_line(&mut line)?;
+    let status = line.split_whitespace().nth(1).unwrap_or("");
+    if status != "200" {
+        return Err(io::Error::other(format!("GET {}: {}", path, line.trim_end())));
+    }
+
+    let mut chunked = false;
+    let mut content_length = None;
+
+    loop {
+        line.clear();
+        if r.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = header.split_once(':') {
+            let value = value.trim();
+            match name.to_ascii_lowercase().as_str() {
+                "transfer-encoding" => chunked = value.eq_ignore_ascii_case("chunked"),
+                "content-length" => content_length = value.parse::<u64>().ok(),
+                _ => (),
+            }
+        }
+    }
+
+    Ok(match (chunked, content_length) {
+        (true, _) => Box::new(Chunked {
+            r,
+            left: 0,
+            done: false,
+        }),
+        (false, Some(len)) => Box::new(r.take(len)),
+        (false, None) => Box::new(r),
+    })
+}
+
+// reader of the chunked transfer encoding
+struct Chunked<R> {
+    r: R,
+    // bytes left in the current chunk
+    left: usize,
+    done: bool,
+}
+
+impl<R: BufRead> Read for Chunked<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.left == 0 {
+            if self.done {
+                return Ok(0);
+            }
+
+            let mut line = String::new();
+            if self.r.read_line(&mut line)? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let size = line.trim_end().split(';').next().unwrap_or("");
+            self.left = usize::from_str_radix(size, 16)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid chunk size"))?;
+
+            if self.left == 0 {
+                self.done = true;
+                return Ok(0);
+            }
+        }
+
+        let len = buf.len().min(self.left);
+        let n = self.r.read(&mut buf[..len])?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.left -= n;
+
+        if self.left == 0 {
+            // the chunk ends with crlf
+            let mut crlf = String::new();
+            self.r.read_line(&mut crlf)?;
+        }
+
+        Ok(n)
+    }
+}
@@ -0,0 +1,178 @@
+use std::sync::OnceLock;
+
+use actix_web::{
+    dev::{ResourceDef, ServiceRequest},
+    web,
+};
+
+use crate::{
+    auth::Role,
+    handlers_admin, handlers_create, handlers_events, handlers_get, handlers_health,
+    handlers_import, handlers_metrics, handlers_replication,
+};
+
+// a route of the server, the handlers are registered with the names
+// of their routes, e.g. #[get("/users/{id}", name = "user")]
+pub struct Route {
+    pub name: &'static str,
+    // GET or POST
+    pub method: &'static str,
+    pub pattern: &'static str,
+    // the role the route needs, None for the public ones
+    pub role: Option<Role>,
+    // the route is served while the data is loading, the others get 503
+    pub while_loading: bool,
+}
+
+const fn route(name: &'static str, method: &'static str, pattern: &'static str, role: Option<Role>) -> Route {
+    Route {
+        name,
+        method,
+        pattern,
+        role,
+        while_loading: false,
+    }
+}
+
+// the followers read the options of the leader, so GET /admin/options is for the readers
+pub const ROUTES: &[Route] = &[
+    route("new_user", "POST", "/users/new", Some(Role::Writer)),
+    route("new_location", "POST", "/locations/new", Some(Role::Writer)),
+    route("new_visit", "POST", "/visits/new", Some(Role::Writer)),
+    route("user", "GET", "/users/{id}", Some(Role::Reader)),
+    route("visit", "GET", "/visits/{id}", Some(Role::Reader)),
+    route("location", "GET", "/locations/{id}", Some(Role::Reader)),
+    route("user_visits", "GET", "/users/{id}/visits", Some(Role::Reader)),
+    route("location_avg", "GET", "/locations/{id}/avg", Some(Role::Reader)),
+    route("admin_snapshot", "POST", "/admin/snapshot", Some(Role::Admin)),
+    route("admin_export", "GET", "/admin/export", Some(Role::Admin)),
+    route("admin_load_report", "GET", "/admin/load-report", Some(Role::Admin)),
+    route("admin_options", "GET", "/admin/options", Some(Role::Reader)),
+    route("admin_reload", "POST", "/admin/reload", Some(Role::Admin)),
+    route("admin_reload", "GET", "/admin/reload", Some(Role::Admin)),
+    route("events", "GET", "/events", Some(Role::Reader)),
+    route("replication_snapshot", "GET", "/replication/snapshot", Some(Role::Reader)),
+    route("import", "POST", "/import", Some(Role::Writer)),
+    Route {
+        while_loading: true,
+        ..route("metrics", "GET", "/metrics", Some(Role::Reader))
+    },
+    Route {
+        while_loading: true,
+        ..route("health", "GET", "/health", None)
+    },
+    Route {
+        while_loading: true,
+        ..route("ready", "GET", "/ready", None)
+    },
+];
+
+// the named routes missing in ROUTES are for the admins only
+const UNLISTED: Route = route("unlisted", "GET", "unlisted", Some(Role::Admin));
+
+// the routes are matched by the path only, so /users/new goes before /users/{id}
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(handlers_create::new_user)
+        .service(handlers_create::new_location)
+        .service(handlers_create::new_visit)
+        .service(handlers_get::users)
+        .service(handlers_get::visits)
+        .service(handlers_get::locations)
+        .service(handlers_get::user_visits)
+        .service(handlers_get::location_avg)
+        .service(handlers_admin::snapshot)
+        .service(handlers_admin::export_data)
+        .service(handlers_admin::load_report)
+        .service(handlers_admin::options)
+        .service(handlers_admin::reload_data)
+        .service(handlers_admin::reload_status)
+        .service(handlers_events::events)
+        .service(handlers_replication::snapshot)
+        .service(handlers_import::import)
+        .service(handlers_metrics::metrics)
+        .service(handlers_health::health)
+        .service(handlers_health::ready);
+}
+
+// the route the request goes to, None if it matches no route. actix routes
+// by the percent-decoded path, e.g. /%61dmin/export goes to /admin/export,
+// so it's matched here too, not the raw path of the request. the routes are
+// matched by the method and the path like actix does, e.g. GET /users/new
+// goes to /users/{id}
+pub fn of(req: &ServiceRequest) -> Option<&'static Route> {
+    static PATTERNS: OnceLock<Vec<ResourceDef>> = OnceLock::new();
+    let patterns = PATTERNS.get_or_init(|| ROUTES.iter().map(|route| ResourceDef::new(route.pattern)).collect());
+
+    let path = req.match_info().as_str();
+    let route = ROUTES
+        .iter()
+        .zip(patterns)
+        .find(|(route, pattern)| route.method == req.method().as_str() && pattern.is_match(path));
+    if let Some((route, _)) = route {
+        return Some(route);
+    }
+
+    // the path of a route with another method gets 404 or 405
+    let name = req.resource_map().match_name(path)?;
+    match ROUTES.iter().any(|route| route.name == name) {
+        true => None,
+        false => Some(&UNLISTED),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        dev::Service,
+        http::{Method, StatusCode},
+        test::{self, TestRequest},
+        App, HttpResponse,
+    };
+    use futures_util::future;
+
+    use super::*;
+
+    // answers with the name of the route instead of calling the handler
+    async fn route_name(uri: &str) -> String {
+        route_name_of("GET", uri).await
+    }
+
+    async fn route_name_of(method: &str, uri: &str) -> String {
+        let app = test::init_service(App::new().configure(configure).wrap_fn(|req, _srv| {
+            let name = of(&req).map_or("none", |route| route.name);
+            future::ready(Ok(req.into_response(HttpResponse::Ok().body(name))))
+        }))
+        .await;
+
+        let res = app.call(TestRequest::default().method(Method::from_bytes(method.as_bytes()).unwrap()).uri(uri).to_request()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        String::from_utf8(test::read_body(res).await.to_vec()).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn every_route_is_registered() {
+        for route in ROUTES {
+            let path = route.pattern.replace("{id}", "1");
+            assert_eq!(route_name_of(route.method, &path).await, route.name, "{}", route.pattern);
+        }
+    }
+
+    #[actix_web::test]
+    async fn matches_the_decoded_path() {
+        assert_eq!(route_name("/%61dmin/export").await, "admin_export");
+        assert_eq!(route_name("/users/%31/visits").await, "user_visits");
+        assert_eq!(route_name_of("POST", "/users/new?x=1").await, "new_user");
+        assert_eq!(route_name("/nothing").await, "none");
+        // %2F isn't decoded by actix
+        assert_eq!(route_name("/admin%2Fexport").await, "none");
+    }
+
+    #[actix_web::test]
+    async fn matches_the_method() {
+        assert_eq!(route_name("/users/new").await, "user");
+        assert_eq!(route_name_of("POST", "/users/new").await, "new_user");
+        assert_eq!(route_name_of("POST", "/admin/reload").await, "admin_reload");
+        assert_eq!(route_name_of("POST", "/users/1").await, "none");
+        assert_eq!(route_name("/import").await, "none");
+    }
+}
@@ -0,0 +1,93 @@
+use std::{
+    process,
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use actix_web::{rt::signal, web};
+
+use crate::{state::AppState, storage::snapshot};
+
+// waits for SIGTERM or SIGINT
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        let mut term = match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(term) => term,
+            Err(e) => {
+                warn!("shutdown: SIGTERM handler: {}", e);
+                let _ = signal::ctrl_c().await;
+                return;
+            }
+        };
+
+        either(term.recv(), signal::ctrl_c()).await;
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = signal::ctrl_c().await;
+    }
+}
+
+// resolves when either of the futures does
+async fn either<A: std::future::Future, B: std::future::Future>(a: A, b: B) {
+    let a = std::pin::pin!(a);
+    let b = std::pin::pin!(b);
+    futures_util::future::select(a, b).await;
+}
+
+// makes the accepted mutations durable after the server has stopped:
+// fsyncs the log, flushes the files of the storage and writes the final
+// snapshot. gives up and exits at the deadline, the log is synced first,
+// so the mutations survive even without the snapshot
+pub fn persist(data: web::Data<AppState>, deadline: Instant) {
+    // nothing is accepted before the data is loaded,
+    // and the snapshot of the partly loaded data would replace the data
+    if !data.is_ready() {
+        info!("shutdown: the data isn't loaded, nothing to persist");
+        return;
+    }
+
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        if let Some(wal) = data.wal.get() {
+            match wal.lock().unwrap().sync() {
+                Ok(()) => info!("shutdown: log synced"),
+                Err(e) => error!("shutdown: log sync error: {}", e),
+            }
+        }
+
+        if let Err(e) = data.write_storage().flush() {
+            error!("shutdown: storage flush error: {}", e);
+        }
+
+        if let Some(dir) = &data.config.snapshot_dir {
+            let last_seq = data.read_storage().last_seq();
+            let latest = snapshot::list(dir).ok().and_then(|s| s.last().map(|(seq, _)| *seq));
+
+            if latest == Some(last_seq) {
+                info!("shutdown: the latest snapshot is up to date");
+            } else {
+                match data.snapshot() {
+                    Ok(path) => info!("shutdown: snapshot written: {}", path.display()),
+                    Err(e) => error!("shutdown: snapshot error: {}", e),
+                }
+            }
+        }
+
+        let _ = tx.send(());
+    });
+
+    let timeout = deadline.saturating_duration_since(Instant::now());
+    match rx.recv_timeout(timeout.max(Duration::from_millis(1))) {
+        Ok(()) => info!("shutdown: done"),
+        Err(RecvTimeoutError::Timeout) => {
+            error!("shutdown: deadline exceeded");
+            process::exit(1);
+        }
+        Err(RecvTimeoutError::Disconnected) => process::exit(1),
+    }
+}
@@ -0,0 +1,288 @@
+use std::{
+    fs, io,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, OnceLock, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    time::Instant,
+};
+
+use serde_json::json;
+
+use crate::{
+    auth::Caller,
+    cache::ResponseCache,
+    config::Config,
+    events::EventBus,
+    load,
+    logging::{self, Level},
+    metrics::{Lock, Metrics},
+    options::{Options, RunMode},
+    reload,
+    mutation::Mutation,
+    storage::{snapshot, StoreError, Storage},
+    wal::Wal,
+};
+
+pub struct AppState {
+    pub config: Config,
+    pub storage: Arc<RwLock<Box<dyn Storage>>>,
+    pub cache: ResponseCache,
+    // set once the log is replayed to the loaded data
+    pub wal: OnceLock<Mutex<Wal>>,
+    pub events: EventBus,
+    // report of the last data load, if the data was loaded from the files
+    pub load_report: Mutex<Option<load::Report>>,
+    // options.txt of the data
+    pub options: Mutex<Option<Options>>,
+    pub reload: Mutex<reload::Status>,
+    // the mutations committed during the reload of the data, see reload.rs
+    pub captured: Mutex<Option<Vec<Mutation>>>,
+    pub metrics: Metrics,
+    // the server starts before the data is loaded, only GET /health, /ready
+    // and /metrics are served until it's set, see handlers_health.rs
+    pub ready: AtomicBool,
+    pub progress: load::Progress,
+}
+
+impl AppState {
+    // the storage locks, the waits are counted in the metrics
+    pub fn read_storage(&self) -> RwLockReadGuard<'_, Box<dyn Storage>> {
+        let start = Instant::now();
+        let s = self.storage.read().unwrap();
+        self.metrics.lock_wait(Lock::Read, start.elapsed());
+        s
+    }
+
+    pub fn write_storage(&self) -> RwLockWriteGuard<'_, Box<dyn Storage>> {
+        let start = Instant::now();
+        let s = self.storage.write().unwrap();
+        self.metrics.lock_wait(Lock::Write, start.elapsed());
+        s
+    }
+
+    // checks the mutation, writes it to the log, applies it to the storage,
+    // refreshes the cached responses and publishes the change event.
+    // everything happens under the storage write lock,
+    // so the log has the same order of mutations as the storage.
+    // the mutation is applied only once it's in the log, and the record is
+    // removed if the storage fails to apply it, so the client gets an error
+    // only for the changes which neither readers nor a restart will see.
+    // the followers of a leader are read-only. the mutations of the
+    // authenticated callers are written to the audit log with their keys
+    pub fn commit(&self, mutation: &Mutation, caller: Option<&Caller>) -> Result<(), StoreError> {
+        if self.config.leader.is_some() {
+            return Err(StoreError::ReadOnly);
+        }
+
+        let mut s = self.write_storage();
+
+        if let Err(e) = mutation.check(&**s) {
+            rejected(mutation, e);
+            return Err(e);
+        }
+
+        // without the log the mutations are numbered by the storage,
+        // the numbers are the ids of the change events
+        let wal = self.wal.get().map(|wal| wal.lock().unwrap());
+        let seq = match wal {
+            Some(mut wal) => {
+                let seq = wal.append(mutation).map_err(|e| {
+                    error!("wal: append error: {}", e);
+                    StoreError::Io
+                })?;
+
+                if let Err(e) = mutation.apply(&mut **s) {
+                    if let Err(e) = wal.revert(seq) {
+                        error!("wal: revert error: {}", e);
+                    }
+                    rejected(mutation, e);
+                    return Err(e);
+                }
+                seq
+            }
+            None => {
+                if let Err(e) = mutation.apply(&mut **s) {
+                    rejected(mutation, e);
+                    return Err(e);
+                }
+                s.last_seq() + 1
+            }
+        };
+        s.set_last_seq(seq);
+
+        if let Some(captured) = &mut *self.captured.lock().unwrap() {
+            captured.push(mutation.clone());
+        }
+
+        self.refresh(&**s, seq, mutation);
+
+        if let Some(caller) = caller {
+            logging::audit(&[
+                ("key", json!(caller.name)),
+                ("role", json!(caller.role.name())),
+                ("mutation", json!(mutation.kind())),
+                ("id", json!(mutation.id())),
+                ("seq", json!(seq)),
+            ]);
+        }
+
+        Ok(())
+    }
+
+    // applies the mutation received from the leader with its sequence number
+    pub fn replicate(&self, seq: u64, mutation: &Mutation) {
+        let mut s = self.write_storage();
+
+        // the follower got the same data as the leader,
+        // so the mutation fails only if it's already applied
+        if let Err(e) = mutation.apply(&mut **s) {
+            warn!("replication: mutation {} rejected: {}", seq, e);
+        }
+        s.set_last_seq(seq);
+
+        self.refresh(&**s, seq, mutation);
+    }
+
+    // replaces the whole storage, e.g. with a new snapshot of the leader
+    pub fn replace_storage(&self, storage: Box<dyn Storage>) {
+        let mut s = self.write_storage();
+        *s = storage;
+
+        self.reset(&**s);
+    }
+
+    // the committed mutations are kept until swap_storage
+    pub fn start_capture(&self) {
+        *self.captured.lock().unwrap() = Some(Vec::new());
+    }
+
+    pub fn stop_capture(&self) {
+        *self.captured.lock().unwrap() = None;
+    }
+
+    // replaces the storage with the newly loaded one, the captured mutations
+    // are applied to it first. the new storage continues the sequence numbers
+    // of the current one, so the log stays in order. with the snapshots
+    // the new storage is saved before the swap under the same lock, so the
+    // log after the snapshot has only its mutations; the swap is cancelled
+    // if the snapshot fails. the reload is logged with the sequence number
+    // of the snapshot, so the log isn't replayed across it to other data.
+    // returns the numbers of the applied and rejected mutations
+    pub fn swap_storage(&self, mut storage: Box<dyn Storage>) -> io::Result<(usize, usize)> {
+        let mut s = self.write_storage();
+
+        let mut applied = 0;
+        let mut rejected = 0;
+
+        if let Some(captured) = self.captured.lock().unwrap().take() {
+            for mutation in &captured {
+                match mutation.apply(&mut *storage) {
+                    Ok(_) => applied += 1,
+                    Err(_) => rejected += 1,
+                }
+            }
+        }
+
+        let mut wal = self.wal.get().map(|wal| wal.lock().unwrap());
+        storage.set_last_seq(match &wal {
+            Some(wal) => wal.next_seq(),
+            None => s.last_seq(),
+        });
+
+        let mut written = None;
+        if let Some(dir) = &self.config.snapshot_dir {
+            let path = snapshot::save(&*storage, dir, self.config.snapshot_keep)?;
+            info!("snapshot written: {}", path.display());
+            written = Some(path);
+        }
+
+        // the snapshot goes first: after a crash before the marker the restart
+        // loads the snapshot, which is newer than all the logged mutations.
+        // without the marker the snapshot is removed, the old data stays
+        if let Some(wal) = &mut wal {
+            if let Err(e) = wal.append_reload() {
+                error!("wal: append error: {}", e);
+                if let Some(path) = written {
+                    fs::remove_file(path)?;
+                }
+                return Err(e);
+            }
+        }
+
+        *s = storage;
+
+        self.reset(&**s);
+
+        Ok((applied, rejected))
+    }
+
+    // the cached responses and the buffered events are of the replaced data
+    fn reset(&self, s: &dyn Storage) {
+        if self.cache.is_enabled() {
+            self.cache.rebuild(s);
+        }
+
+        self.events.reset(s.last_seq());
+    }
+
+    fn refresh(&self, s: &dyn Storage, seq: u64, mutation: &Mutation) {
+        match mutation {
+            Mutation::NewUser(user) => self.cache.refresh_user(s, user.id),
+            Mutation::NewLocation(location) => self.cache.refresh_location(s, location.id),
+            Mutation::NewVisit(visit) => self.cache.refresh_visit(s, visit.id),
+        }
+
+        self.events.publish(seq, mutation);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Acquire)
+    }
+
+    pub fn set_ready(&self) {
+        self.ready.store(true, Ordering::Release);
+    }
+
+    // the data without the options is treated as the test one
+    pub fn run_mode(&self) -> RunMode {
+        self.options
+            .lock()
+            .unwrap()
+            .map(|o| o.mode)
+            .unwrap_or(RunMode::Test)
+    }
+
+    // writes the snapshot of the storage to the snapshots directory.
+    // the storage is read-locked while writing, so the snapshot
+    // matches the log sequence number in its name
+    pub fn snapshot(&self) -> io::Result<PathBuf> {
+        let dir = match &self.config.snapshot_dir {
+            Some(dir) => dir,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "snapshots directory is not configured",
+                ))
+            }
+        };
+
+        let s = self.read_storage();
+        snapshot::save(&**s, dir, self.config.snapshot_keep)
+    }
+}
+
+fn rejected(mutation: &Mutation, e: StoreError) {
+    if logging::enabled(Level::Info) {
+        logging::log(
+            Level::Info,
+            format_args!("write rejected: {}", e),
+            &[
+                ("mutation", json!(mutation.kind())),
+                ("id", json!(mutation.id())),
+                ("reason", json!(e)),
+            ],
+        );
+    }
+}
@@ -0,0 +1,440 @@
+use std::{collections::HashSet, io};
+
+use crate::{dict::Dict, idvec::IdVec, model};
+use chrono::{DateTime, NaiveDateTime, Utc};
+
+use super::{snapshot, sort_visit_indexes, Counts, QueryError, StoreError, Storage};
+
+// in-memory storage, all the entities are kept on the heap
+#[derive(Default)]
+pub struct MemStorage {
+    pub(super) users: IdVec<model::User>,
+    pub(super) visits: IdVec<model::Visit>,
+    pub(super) locations: IdVec<model::Location>,
+
+    // for duplications check purposes
+    pub(super) emails: HashSet<String>,
+
+    pub(super) last_names: Dict,
+    pub(super) first_names: Dict,
+    pub(super) countries: Dict,
+    pub(super) cities: Dict,
+    pub(super) places: Dict,
+
+    pub(super) timestamp: i64,
+    pub(super) last_seq: u64,
+
+    // the visits aren't indexed while it's set, see Storage::begin_bulk_load
+    bulk_load: bool,
+}
+
+impl MemStorage {
+    pub fn new() -> Self {
+        MemStorage {
+            users: IdVec::new(),
+            visits: IdVec::new(),
+            locations: IdVec::new(),
+
+            emails: HashSet::new(),
+
+            last_names: Dict::new(),
+            first_names: Dict::new(),
+            countries: Dict::new(),
+            cities: Dict::new(),
+            places: Dict::new(),
+
+            timestamp: 0,
+            last_seq: 0,
+
+            bulk_load: false,
+        }
+    }
+}
+
+impl Storage for MemStorage {
+    fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    fn set_timestamp(&mut self, timestamp: i64) {
+        self.timestamp = timestamp;
+    }
+
+    fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
+
+    fn set_last_seq(&mut self, seq: u64) {
+        self.last_seq = seq;
+    }
+
+    fn user(&self, id: u32) -> Option<model::UserRef<'_>> {
+        let user = self.users.get(id)?;
+
+        Some(model::UserRef {
+            id,
+            email: &user.email,
+            first_name: self.first_names.get_by_idx(user.first_name as usize),
+            last_name: self.last_names.get_by_idx(user.last_name as usize),
+            gender: user.gender.as_str(),
+            birth_date: user.birth_date,
+        })
+    }
+
+    fn location(&self, id: u32) -> Option<model::LocationRef<'_>> {
+        let location = self.locations.get(id)?;
+
+        Some(model::LocationRef {
+            id,
+            country: self.countries.get_by_idx(location.country as usize),
+            city: self.cities.get_by_idx(location.city as usize),
+            place: self.places.get_by_idx(location.place as usize),
+            distance: location.distance,
+        })
+    }
+
+    fn visit(&self, id: u32) -> Option<model::VisitJSON> {
+        let visit = self.visits.get(id)?;
+
+        Some(model::VisitJSON {
+            id,
+            location: visit.location,
+            user: visit.user,
+            mark: visit.mark,
+            visited_at: visit.visited_at,
+        })
+    }
+
+    fn has_email(&self, email: &str) -> bool {
+        self.emails.contains(email)
+    }
+
+    fn user_visits(
+        &self,
+        id: u32,
+        params: &model::UserVisitsParams,
+    ) -> Result<Vec<model::UserVisitRef<'_>>, QueryError> {
+        let user = self.users.get(id).ok_or(QueryError::NotFound)?;
+
+        let mut country_id = None;
+
+        if let Some(country) = params.country.as_deref() {
+            if country.is_empty() {
+                return Err(QueryError::BadRequest);
+            }
+            match self.countries.map.get(country) {
+                Some(id) => country_id = Some(*id),
+                None => return Err(QueryError::NotFound),
+            }
+        }
+
+        let mut end_idx = user.visits.len();
+        let mut start_idx: usize = 0;
+
+        if let Some(to_date) = params.to_date {
+            // binary search of the last index to iterate to
+            end_idx = user.visits.partition_point(|x| x.visited_at < to_date);
+        }
+        if let Some(from_date) = params.from_date {
+            // binary search of the first index to iterate from
+            start_idx = user.visits.partition_point(|x| x.visited_at <= from_date);
+        }
+
+        let mut visits = Vec::with_capacity(end_idx.saturating_sub(start_idx));
+
+        for user_visit in user.visits.iter().take(end_idx).skip(start_idx) {
+            let location = &self.locations[user_visit.location];
+
+            if let Some(country_id) = country_id {
+                if location.country != country_id {
+                    continue;
+                }
+            }
+            if let Some(to_distance) = params.to_distance {
+                if location.distance >= to_distance {
+                    continue;
+                }
+            }
+
+            let visit = &self.visits[user_visit.id];
+
+            visits.push(model::UserVisitRef {
+                mark: visit.mark,
+                visited_at: visit.visited_at,
+                place: self.places.get_by_idx(location.place as usize),
+            })
+        }
+
+        Ok(visits)
+    }
+
+    fn location_avg(&self, id: u32, params: &model::LocationAvgParams) -> Result<f64, QueryError> {
+        let location = self.locations.get(id).ok_or(QueryError::NotFound)?;
+
+        let gender = params.gender.as_deref().map(model::Gender::from);
+
+        let mut end_idx = location.visits.len();
+        let mut start_idx: usize = 0;
+
+        if let Some(to_date) = params.to_date {
+            // binary search of the last index to iterate to
+            end_idx = location.visits.partition_point(|x| x.visited_at < to_date);
+        }
+        if let Some(from_date) = params.from_date {
+            // binary search of the first index to iterate from
+            start_idx = location.visits.partition_point(|x| x.visited_at <= from_date);
+        }
+
+        let mut count: u32 = 0;
+        let mut total_mark: i32 = 0;
+
+        for location_visit in location.visits.iter().take(end_idx).skip(start_idx) {
+            let visit = &self.visits[location_visit.visit_id];
+            let user = &self.users[visit.user];
+
+            if let Some(from_age) = params.from_age {
+                if user.age <= from_age as u8 {
+                    continue;
+                }
+            }
+
+            if let Some(to_age) = params.to_age {
+                if user.age >= to_age as u8 {
+                    continue;
+                }
+            }
+
+            if let Some(gender) = &gender {
+                if user.gender != *gender {
+                    continue;
+                }
+            }
+
+            total_mark += visit.mark as i32;
+            count += 1;
+        }
+
+        if count == 0 {
+            return Ok(0.0);
+        }
+
+        let answer = total_mark as f64 / count as f64;
+
+        Ok((answer * 100000.0).round() / 100000.0)
+    }
+
+    fn reserve(&mut self, counts: Counts) {
+        self.users.reserve(counts.users);
+        self.visits.reserve(counts.visits);
+        self.locations.reserve(counts.locations);
+    }
+
+    fn store_user(&mut self, user: &model::UserJSON) -> Result<(), StoreError> {
+        if self.users.contains(user.id) {
+            return Err(StoreError::UserExists);
+        }
+        if self.emails.contains(&user.email) {
+            return Err(StoreError::EmailExists);
+        }
+
+        let gender = model::Gender::from(user.gender.as_str());
+        if matches!(gender, model::Gender::None) {
+            return Err(StoreError::UnknownGender);
+        }
+
+        let curr_date_time: DateTime<Utc> = DateTime::from_utc(
+            NaiveDateTime::from_timestamp_opt(self.timestamp, 0).unwrap(),
+            Utc,
+        );
+        let birth_date_time = DateTime::from_utc(
+            NaiveDateTime::from_timestamp_opt(user.birth_date.into(), 0).unwrap(),
+            Utc,
+        );
+
+        let stored = model::User {
+            email: user.email.clone(),
+            first_name: self.first_names.put(user.first_name.clone()),
+            last_name: self.last_names.put(user.last_name.clone()),
+            birth_date: user.birth_date,
+            age: curr_date_time.years_since(birth_date_time).unwrap_or(0) as u8,
+            gender,
+            visits: Vec::new(),
+        };
+
+        self.users.insert(user.id, stored);
+        self.emails.insert(user.email.clone());
+
+        Ok(())
+    }
+
+    fn store_location(&mut self, location: &model::LocationJSON) -> Result<(), StoreError> {
+        if self.locations.contains(location.id) {
+            return Err(StoreError::LocationExists);
+        }
+
+        let stored = model::Location {
+            country: self.countries.put(location.country.clone()),
+            city: self.cities.put(location.city.clone()),
+            place: self.places.put(location.place.clone()),
+            distance: location.distance,
+            visits: Vec::new(),
+        };
+        self.locations.insert(location.id, stored);
+
+        Ok(())
+    }
+
+    fn store_visit(&mut self, visit: &model::VisitJSON) -> Result<(), StoreError> {
+        let (id, visited_at) = (visit.id, visit.visited_at);
+
+        if self.visits.contains(id) {
+            return Err(StoreError::VisitExists);
+        }
+
+        let stored = model::Visit {
+            user: visit.user,
+            location: visit.location,
+            visited_at,
+            mark: visit.mark,
+        };
+
+        // the indexes are built at the end of the bulk load,
+        // when all the users and locations are there
+        if self.bulk_load {
+            self.visits.insert(id, stored);
+            return Ok(());
+        }
+
+        if !self.users.contains(visit.user) {
+            return Err(StoreError::UserNotFound);
+        }
+        if !self.locations.contains(visit.location) {
+            return Err(StoreError::LocationNotFound);
+        }
+
+        self.visits.insert(id, stored);
+
+        let user = self.users.get_mut(visit.user).unwrap();
+
+        let user_visit = model::UserVisit {
+            id,
+            visited_at,
+            location: visit.location,
+        };
+
+        // inserting to the sorted vector of user visits
+        let user_visit_idx = user.visits.partition_point(|x| x.visited_at < visited_at);
+        user.visits.insert(user_visit_idx, user_visit);
+
+        let location_visit = model::LocationVisit {
+            visit_id: id,
+            visited_at,
+        };
+
+        let location = self.locations.get_mut(visit.location).unwrap();
+
+        // inserting to the sorted vector of location visits
+        let location_visit_idx = location
+            .visits
+            .partition_point(|x| x.visited_at < visited_at);
+        location.visits.insert(location_visit_idx, location_visit);
+
+        Ok(())
+    }
+
+    fn begin_bulk_load(&mut self) {
+        self.bulk_load = true;
+    }
+
+    fn end_bulk_load(&mut self, threads: usize) -> Vec<(u32, StoreError)> {
+        if !self.bulk_load {
+            return Vec::new();
+        }
+        self.bulk_load = false;
+
+        // the indexes are rebuilt from all the visits,
+        // appended unsorted and then sorted once
+        for user in self.users.values_mut() {
+            user.visits.clear();
+        }
+        for location in self.locations.values_mut() {
+            location.visits.clear();
+        }
+
+        let mut orphans = Vec::new();
+
+        for (id, visit) in self.visits.iter() {
+            let (user, location) = match (
+                self.users.get_mut(visit.user),
+                self.locations.get_mut(visit.location),
+            ) {
+                (Some(user), Some(location)) => (user, location),
+                (None, _) => {
+                    orphans.push((id, StoreError::UserNotFound));
+                    continue;
+                }
+                (_, None) => {
+                    orphans.push((id, StoreError::LocationNotFound));
+                    continue;
+                }
+            };
+
+            user.visits.push(model::UserVisit {
+                id,
+                visited_at: visit.visited_at,
+                location: visit.location,
+            });
+            location.visits.push(model::LocationVisit {
+                visit_id: id,
+                visited_at: visit.visited_at,
+            });
+        }
+
+        for (id, _) in &orphans {
+            self.visits.remove(*id);
+        }
+
+        let users = self.users.values_mut().map(|u| &mut u.visits).collect();
+        sort_visit_indexes(users, threads, |v| v.visited_at);
+
+        let locations = self.locations.values_mut().map(|l| &mut l.visits).collect();
+        sort_visit_indexes(locations, threads, |v| v.visited_at);
+
+        orphans
+    }
+
+    fn write_snapshot(&self, w: &mut dyn io::Write) -> io::Result<()> {
+        snapshot::write(self, w)
+    }
+
+    fn user_ids(&self) -> Box<dyn Iterator<Item = u32> + '_> {
+        Box::new(self.users.iter().map(|(id, _)| id))
+    }
+
+    fn location_ids(&self) -> Box<dyn Iterator<Item = u32> + '_> {
+        Box::new(self.locations.iter().map(|(id, _)| id))
+    }
+
+    fn visit_ids(&self) -> Box<dyn Iterator<Item = u32> + '_> {
+        Box::new(self.visits.iter().map(|(id, _)| id))
+    }
+
+    fn counts(&self) -> Counts {
+        Counts {
+            users: self.users.len(),
+            visits: self.visits.len(),
+            locations: self.locations.len(),
+        }
+    }
+
+    fn dict_sizes(&self) -> Vec<(&'static str, usize)> {
+        vec![
+            ("emails", self.emails.len()),
+            ("first_names", self.first_names.entries().len()),
+            ("last_names", self.last_names.entries().len()),
+            ("countries", self.countries.entries().len()),
+            ("cities", self.cities.entries().len()),
+            ("places", self.places.entries().len()),
+        ]
+    }
+}
@@ -0,0 +1,1372 @@
+use std::{
+    cmp::Reverse,
+    fs::{self, File, OpenOptions},
+    io,
+    iter,
+    path::{Path, PathBuf},
+    str,
+};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use memmap2::MmapMut;
+
+use crate::model;
+
+use super::{Counts, QueryError, StoreError, Storage};
+
+// storage backed by memory-mapped files in a directory.
+// numeric fields of the entities are kept in fixed-size records
+// at offset id * record size, strings are kept in append-only heaps
+// with on-disk hash tables. the visits of every user and location are
+// linked lists through the visit records sorted by visited_at, and the
+// ids of the stored entities are kept in sorted lists, so opening the
+// storage reads nothing and only the touched pages get into the memory
+pub struct MmapStorage {
+    dir: PathBuf,
+    meta: Meta,
+
+    users: Table,
+    visits: Table,
+    locations: Table,
+
+    emails: MmapDict,
+    first_names: MmapDict,
+    last_names: MmapDict,
+    countries: MmapDict,
+    cities: MmapDict,
+    places: MmapDict,
+
+    // the visits aren't indexed while it's set, see Storage::begin_bulk_load
+    bulk_load: bool,
+    // set when building the visit indexes failed, the storage can't be completed
+    index_failed: bool,
+    // set on the first change after the open or the flush, see begin_change
+    changed: bool,
+}
+
+const META_FILE: &str = "meta.bin";
+const META_MAGIC: &[u8; 4] = b"HLMM";
+const META_VERSION: u32 = 3;
+
+const USER_RECORD: u64 = 24;
+const VISIT_RECORD: u64 = 24;
+const LOCATION_RECORD: u64 = 24;
+
+// offsets of the visit lists fields: the first visit in the user and location
+// records, the next visit of the same user and location in the visit records
+const FIRST_VISIT: u64 = 20;
+const NEXT_USER_VISIT: u64 = 16;
+const NEXT_LOCATION_VISIT: u64 = 20;
+// the end of a visits list
+const NIL: u32 = u32::MAX;
+
+// ids up to it are accepted, so an occasional huge id
+// doesn't make a records file span the whole u32 range
+pub const MAX_ID: u32 = (1 << 26) - 1;
+
+// header of every mapped file keeps the length of the used space
+const HEADER: u64 = 8;
+const MIN_FILE_SIZE: u64 = 4096;
+
+#[derive(Clone, Copy)]
+struct Meta {
+    timestamp: i64,
+    // the meta is written only on flush, the files
+    // changed after it are dropped on the next open
+    last_seq: u64,
+    // set once the initial dataset has been loaded completely,
+    // unset on the disk while the files have changes not flushed
+    complete: bool,
+}
+
+#[derive(Clone, Copy)]
+enum List {
+    User,
+    Location,
+}
+
+impl MmapStorage {
+    // opens the storage in the directory, creates the directory if it doesn't exist
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+
+        let meta = read_meta(&dir.join(META_FILE))?;
+        if !meta.complete {
+            // leftovers of an interrupted load, the dataset is loaded from scratch
+            remove_files(dir)?;
+        }
+
+        Ok(MmapStorage {
+            dir: dir.to_path_buf(),
+            meta,
+
+            users: Table::open(dir, "users", USER_RECORD)?,
+            visits: Table::open(dir, "visits", VISIT_RECORD)?,
+            locations: Table::open(dir, "locations", LOCATION_RECORD)?,
+
+            emails: MmapDict::open(dir, "emails")?,
+            first_names: MmapDict::open(dir, "first_names")?,
+            last_names: MmapDict::open(dir, "last_names")?,
+            countries: MmapDict::open(dir, "countries")?,
+            cities: MmapDict::open(dir, "cities")?,
+            places: MmapDict::open(dir, "places")?,
+
+            bulk_load: false,
+            index_failed: false,
+            changed: false,
+        })
+    }
+
+    // whether the initial dataset has been loaded completely,
+    // otherwise the storage has to be filled from the data files
+    pub fn is_complete(&self) -> bool {
+        self.meta.complete
+    }
+
+    pub fn mark_complete(&mut self) -> io::Result<()> {
+        if self.index_failed {
+            return Err(io::Error::other("mmap: the visit indexes are incomplete"));
+        }
+
+        self.meta.complete = true;
+        self.flush_files()
+    }
+
+    fn flush_files(&mut self) -> io::Result<()> {
+        for t in [&self.users, &self.visits, &self.locations] {
+            t.records.map.flush()?;
+            t.ids.map.flush()?;
+        }
+        for d in [
+            &self.emails,
+            &self.first_names,
+            &self.last_names,
+            &self.countries,
+            &self.cities,
+            &self.places,
+        ] {
+            d.idx.map.flush()?;
+            d.heap.map.flush()?;
+            d.table.map.flush()?;
+        }
+
+        write_meta(&self.dir.join(META_FILE), &self.meta)?;
+        self.changed = false;
+
+        Ok(())
+    }
+
+    // a crash in the middle of a change may leave a record which isn't in
+    // the ids or the visit lists yet, so before the first change the complete
+    // storage is marked incomplete on the disk until the next flush.
+    // after a crash the dataset is loaded again and the whole log is replayed
+    fn begin_change(&mut self) -> io::Result<()> {
+        if self.meta.complete && !self.changed {
+            let meta = Meta {
+                complete: false,
+                ..self.meta
+            };
+            write_meta(&self.dir.join(META_FILE), &meta)?;
+            self.changed = true;
+        }
+
+        Ok(())
+    }
+
+    // builds the visit lists from all the visit records. the visits are prepended
+    // to the lists from the latest one, so every list is sorted by visited_at and
+    // the visits with the same visited_at end up as with the insertion one by one:
+    // the later goes first. the records of the visits of unknown users or
+    // locations are cleared, returns their ids with the reasons
+    fn index_all_visits(&mut self) -> io::Result<Vec<(u32, StoreError)>> {
+        for i in 0..self.users.len() {
+            let id = self.users.id_at(i);
+            self.users.write_u32(id, FIRST_VISIT, NIL)?;
+        }
+        for i in 0..self.locations.len() {
+            let id = self.locations.id_at(i);
+            self.locations.write_u32(id, FIRST_VISIT, NIL)?;
+        }
+
+        let mut orphans = Vec::new();
+        let mut order = Vec::with_capacity(self.visits.len());
+
+        for i in 0..self.visits.len() {
+            let id = self.visits.id_at(i);
+            let visit = self.visit_record(id).unwrap();
+
+            if !self.users.contains(visit.user) {
+                orphans.push((id, StoreError::UserNotFound));
+            } else if !self.locations.contains(visit.location) {
+                orphans.push((id, StoreError::LocationNotFound));
+            } else {
+                order.push((visit.visited_at, id));
+            }
+        }
+
+        for (id, _) in &orphans {
+            self.visits.clear(*id)?;
+        }
+        // the ids are appended unsorted while loading
+        for t in [&mut self.users, &mut self.visits, &mut self.locations] {
+            t.sort_ids()?;
+        }
+
+        order.sort_unstable_by_key(|&(visited_at, id)| (Reverse(visited_at), id));
+
+        for (_, id) in order {
+            let visit = self.visit_record(id).unwrap();
+
+            let first = self.first_visit(List::User, visit.user);
+            self.visits.write_u32(id, NEXT_USER_VISIT, first)?;
+            self.users.write_u32(visit.user, FIRST_VISIT, id)?;
+
+            let first = self.first_visit(List::Location, visit.location);
+            self.visits.write_u32(id, NEXT_LOCATION_VISIT, first)?;
+            self.locations.write_u32(visit.location, FIRST_VISIT, id)?;
+        }
+
+        Ok(orphans)
+    }
+
+    // inserts the visit to the lists of its user and its location,
+    // before the visits with the same or later visited_at
+    fn index_visit(&mut self, id: u32, visit: &VisitRecord) -> io::Result<()> {
+        self.link(List::User, visit.user, id, visit.visited_at)?;
+        self.link(List::Location, visit.location, id, visit.visited_at)
+    }
+
+    fn link(&mut self, list: List, owner: u32, id: u32, visited_at: i32) -> io::Result<()> {
+        let mut prev = None;
+        let mut next = self.first_visit(list, owner);
+
+        while next != NIL {
+            let visit = self.visit_record(next).unwrap();
+            if visit.visited_at >= visited_at {
+                break;
+            }
+            prev = Some(next);
+            next = visit.next(list);
+        }
+
+        self.visits.write_u32(id, list.next_field(), next)?;
+
+        match prev {
+            Some(prev) => self.visits.write_u32(prev, list.next_field(), id),
+            None => self.owners_mut(list).write_u32(owner, FIRST_VISIT, id),
+        }
+    }
+
+    fn first_visit(&self, list: List, owner: u32) -> u32 {
+        match list {
+            List::User => self.user_record(owner).map(|u| u.first_visit),
+            List::Location => self.location_record(owner).map(|l| l.first_visit),
+        }
+        .unwrap_or(NIL)
+    }
+
+    fn owners_mut(&mut self, list: List) -> &mut Table {
+        match list {
+            List::User => &mut self.users,
+            List::Location => &mut self.locations,
+        }
+    }
+
+    // visits of the list in the order of visited_at
+    fn list_visits(&self, list: List, first: u32) -> impl Iterator<Item = VisitRecord> + '_ {
+        let first = (first != NIL).then(|| self.visit_record(first).unwrap());
+
+        iter::successors(first, move |visit| match visit.next(list) {
+            NIL => None,
+            next => Some(self.visit_record(next).unwrap()),
+        })
+    }
+
+    fn user_record(&self, id: u32) -> Option<UserRecord> {
+        let b = self.users.record(id)?;
+
+        Some(UserRecord {
+            gender: b[1],
+            age: b[2],
+            birth_date: read_i32(b, 4),
+            first_name: read_u32(b, 8),
+            last_name: read_u32(b, 12),
+            email: read_u32(b, 16),
+            first_visit: read_u32(b, 20),
+        })
+    }
+
+    fn visit_record(&self, id: u32) -> Option<VisitRecord> {
+        let b = self.visits.record(id)?;
+
+        Some(VisitRecord {
+            mark: b[1],
+            user: read_u32(b, 4),
+            location: read_u32(b, 8),
+            visited_at: read_i32(b, 12),
+            next_user_visit: read_u32(b, 16),
+            next_location_visit: read_u32(b, 20),
+        })
+    }
+
+    fn location_record(&self, id: u32) -> Option<LocationRecord> {
+        let b = self.locations.record(id)?;
+
+        Some(LocationRecord {
+            distance: read_u32(b, 4),
+            country: read_u32(b, 8),
+            city: read_u32(b, 12),
+            place: read_u32(b, 16),
+            first_visit: read_u32(b, 20),
+        })
+    }
+
+    fn age(&self, birth_date: i32) -> u8 {
+        let curr_date_time: DateTime<Utc> = DateTime::from_utc(
+            NaiveDateTime::from_timestamp_opt(self.meta.timestamp, 0).unwrap(),
+            Utc,
+        );
+        let birth_date_time = DateTime::from_utc(
+            NaiveDateTime::from_timestamp_opt(birth_date.into(), 0).unwrap(),
+            Utc,
+        );
+
+        curr_date_time.years_since(birth_date_time).unwrap_or(0) as u8
+    }
+}
+
+impl List {
+    fn next_field(self) -> u64 {
+        match self {
+            List::User => NEXT_USER_VISIT,
+            List::Location => NEXT_LOCATION_VISIT,
+        }
+    }
+}
+
+impl Storage for MmapStorage {
+    fn timestamp(&self) -> i64 {
+        self.meta.timestamp
+    }
+
+    fn set_timestamp(&mut self, timestamp: i64) {
+        self.meta.timestamp = timestamp;
+    }
+
+    fn last_seq(&self) -> u64 {
+        self.meta.last_seq
+    }
+
+    fn set_last_seq(&mut self, seq: u64) {
+        self.meta.last_seq = seq;
+    }
+
+    fn user(&self, id: u32) -> Option<model::UserRef<'_>> {
+        let user = self.user_record(id)?;
+
+        Some(model::UserRef {
+            id,
+            email: self.emails.get(user.email),
+            first_name: self.first_names.get(user.first_name),
+            last_name: self.last_names.get(user.last_name),
+            gender: model::Gender::from_code(user.gender).as_str(),
+            birth_date: user.birth_date,
+        })
+    }
+
+    fn location(&self, id: u32) -> Option<model::LocationRef<'_>> {
+        let location = self.location_record(id)?;
+
+        Some(model::LocationRef {
+            id,
+            country: self.countries.get(location.country),
+            city: self.cities.get(location.city),
+            place: self.places.get(location.place),
+            distance: location.distance,
+        })
+    }
+
+    fn visit(&self, id: u32) -> Option<model::VisitJSON> {
+        let visit = self.visit_record(id)?;
+
+        Some(model::VisitJSON {
+            id,
+            location: visit.location,
+            user: visit.user,
+            mark: visit.mark,
+            visited_at: visit.visited_at,
+        })
+    }
+
+    fn has_email(&self, email: &str) -> bool {
+        self.emails.find(email).is_some()
+    }
+
+    fn user_visits(
+        &self,
+        id: u32,
+        params: &model::UserVisitsParams,
+    ) -> Result<Vec<model::UserVisitRef<'_>>, QueryError> {
+        let user = self.user_record(id).ok_or(QueryError::NotFound)?;
+
+        let mut country_id = None;
+
+        if let Some(country) = params.country.as_deref() {
+            if country.is_empty() {
+                return Err(QueryError::BadRequest);
+            }
+            match self.countries.find(country) {
+                Some(id) => country_id = Some(id),
+                None => return Err(QueryError::NotFound),
+            }
+        }
+
+        let mut visits = Vec::new();
+
+        for visit in self.list_visits(List::User, user.first_visit) {
+            if let Some(to_date) = params.to_date {
+                if visit.visited_at >= to_date {
+                    break;
+                }
+            }
+            if let Some(from_date) = params.from_date {
+                if visit.visited_at <= from_date {
+                    continue;
+                }
+            }
+
+            let location = self.location_record(visit.location).unwrap();
+
+            if let Some(country_id) = country_id {
+                if location.country != country_id {
+                    continue;
+                }
+            }
+            if let Some(to_distance) = params.to_distance {
+                if location.distance >= to_distance {
+                    continue;
+                }
+            }
+
+            visits.push(model::UserVisitRef {
+                mark: visit.mark,
+                visited_at: visit.visited_at,
+                place: self.places.get(location.place),
+            })
+        }
+
+        Ok(visits)
+    }
+
+    fn location_avg(&self, id: u32, params: &model::LocationAvgParams) -> Result<f64, QueryError> {
+        let location = self.location_record(id).ok_or(QueryError::NotFound)?;
+
+        let gender_param = params.gender.as_deref().map(model::Gender::from);
+
+        let mut count: u32 = 0;
+        let mut total_mark: i32 = 0;
+
+        for visit in self.list_visits(List::Location, location.first_visit) {
+            if let Some(to_date) = params.to_date {
+                if visit.visited_at >= to_date {
+                    break;
+                }
+            }
+            if let Some(from_date) = params.from_date {
+                if visit.visited_at <= from_date {
+                    continue;
+                }
+            }
+
+            let user = self.user_record(visit.user).unwrap();
+
+            if let Some(from_age) = params.from_age {
+                if user.age <= from_age as u8 {
+                    continue;
+                }
+            }
+
+            if let Some(to_age) = params.to_age {
+                if user.age >= to_age as u8 {
+                    continue;
+                }
+            }
+
+            if let Some(g) = &gender_param {
+                if model::Gender::from_code(user.gender) != *g {
+                    continue;
+                }
+            }
+
+            total_mark += visit.mark as i32;
+            count += 1;
+        }
+
+        if count == 0 {
+            return Ok(0.0);
+        }
+
+        let answer = total_mark as f64 / count as f64;
+
+        Ok((answer * 100000.0).round() / 100000.0)
+    }
+
+    fn reserve(&mut self, counts: Counts) {
+        // the records files are sparse, so it only saves the remapping while loading
+        let max = MAX_ID as usize + 1;
+        let _ = self.users.reserve(counts.users.min(max));
+        let _ = self.visits.reserve(counts.visits.min(max));
+        let _ = self.locations.reserve(counts.locations.min(max));
+    }
+
+    fn store_user(&mut self, user: &model::UserJSON) -> Result<(), StoreError> {
+        if self.users.contains(user.id) {
+            return Err(StoreError::UserExists);
+        }
+        if self.emails.find(&user.email).is_some() {
+            return Err(StoreError::EmailExists);
+        }
+
+        let gender = model::Gender::from(user.gender.as_str());
+        if matches!(gender, model::Gender::None) {
+            return Err(StoreError::UnknownGender);
+        }
+        if user.id > MAX_ID {
+            return Err(StoreError::IdOutOfRange);
+        }
+
+        self.begin_change()?;
+
+        let mut b = [0u8; USER_RECORD as usize];
+        b[0] = 1;
+        b[1] = gender.code();
+        b[2] = self.age(user.birth_date);
+        b[4..8].copy_from_slice(&user.birth_date.to_le_bytes());
+        b[8..12].copy_from_slice(&self.first_names.put(&user.first_name)?.to_le_bytes());
+        b[12..16].copy_from_slice(&self.last_names.put(&user.last_name)?.to_le_bytes());
+        b[16..20].copy_from_slice(&self.emails.put(&user.email)?.to_le_bytes());
+        b[20..24].copy_from_slice(&NIL.to_le_bytes());
+
+        self.users.insert(user.id, &b, !self.bulk_load)?;
+
+        Ok(())
+    }
+
+    fn store_location(&mut self, location: &model::LocationJSON) -> Result<(), StoreError> {
+        if self.locations.contains(location.id) {
+            return Err(StoreError::LocationExists);
+        }
+        if location.id > MAX_ID {
+            return Err(StoreError::IdOutOfRange);
+        }
+
+        self.begin_change()?;
+
+        let mut b = [0u8; LOCATION_RECORD as usize];
+        b[0] = 1;
+        b[4..8].copy_from_slice(&location.distance.to_le_bytes());
+        b[8..12].copy_from_slice(&self.countries.put(&location.country)?.to_le_bytes());
+        b[12..16].copy_from_slice(&self.cities.put(&location.city)?.to_le_bytes());
+        b[16..20].copy_from_slice(&self.places.put(&location.place)?.to_le_bytes());
+        b[20..24].copy_from_slice(&NIL.to_le_bytes());
+
+        self.locations.insert(location.id, &b, !self.bulk_load)?;
+
+        Ok(())
+    }
+
+    fn store_visit(&mut self, visit: &model::VisitJSON) -> Result<(), StoreError> {
+        if self.visits.contains(visit.id) {
+            return Err(StoreError::VisitExists);
+        }
+        // in the bulk load the users and locations may come later
+        if !self.bulk_load {
+            if !self.users.contains(visit.user) {
+                return Err(StoreError::UserNotFound);
+            }
+            if !self.locations.contains(visit.location) {
+                return Err(StoreError::LocationNotFound);
+            }
+        }
+        if visit.id > MAX_ID {
+            return Err(StoreError::IdOutOfRange);
+        }
+
+        self.begin_change()?;
+
+        let mut b = [0u8; VISIT_RECORD as usize];
+        b[0] = 1;
+        b[1] = visit.mark;
+        b[4..8].copy_from_slice(&visit.user.to_le_bytes());
+        b[8..12].copy_from_slice(&visit.location.to_le_bytes());
+        b[12..16].copy_from_slice(&visit.visited_at.to_le_bytes());
+        b[16..24].fill(0xff);
+
+        self.visits.insert(visit.id, &b, !self.bulk_load)?;
+
+        if !self.bulk_load {
+            let record = self.visit_record(visit.id).unwrap();
+            self.index_visit(visit.id, &record)?;
+        }
+
+        Ok(())
+    }
+
+    fn begin_bulk_load(&mut self) {
+        self.bulk_load = true;
+    }
+
+    fn end_bulk_load(&mut self, _threads: usize) -> Vec<(u32, StoreError)> {
+        if !self.bulk_load {
+            return Vec::new();
+        }
+        self.bulk_load = false;
+
+        match self.index_all_visits() {
+            Ok(orphans) => orphans,
+            Err(e) => {
+                error!("mmap: building the visit indexes: {}", e);
+                self.index_failed = true;
+                Vec::new()
+            }
+        }
+    }
+
+    // flushes all the mapped files and the meta to the disk
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_files()
+    }
+
+    fn user_ids(&self) -> Box<dyn Iterator<Item = u32> + '_> {
+        Box::new(self.users.ids())
+    }
+
+    fn location_ids(&self) -> Box<dyn Iterator<Item = u32> + '_> {
+        Box::new(self.locations.ids())
+    }
+
+    fn visit_ids(&self) -> Box<dyn Iterator<Item = u32> + '_> {
+        Box::new(self.visits.ids())
+    }
+
+    fn counts(&self) -> Counts {
+        Counts {
+            users: self.users.len(),
+            visits: self.visits.len(),
+            locations: self.locations.len(),
+        }
+    }
+
+    fn dict_sizes(&self) -> Vec<(&'static str, usize)> {
+        vec![
+            ("emails", self.emails.len()),
+            ("first_names", self.first_names.len()),
+            ("last_names", self.last_names.len()),
+            ("countries", self.countries.len()),
+            ("cities", self.cities.len()),
+            ("places", self.places.len()),
+        ]
+    }
+}
+
+struct UserRecord {
+    gender: u8,
+    age: u8,
+    birth_date: i32,
+    first_name: u32,
+    last_name: u32,
+    email: u32,
+    first_visit: u32,
+}
+
+struct VisitRecord {
+    mark: u8,
+    user: u32,
+    location: u32,
+    visited_at: i32,
+    next_user_visit: u32,
+    next_location_visit: u32,
+}
+
+impl VisitRecord {
+    fn next(&self, list: List) -> u32 {
+        match list {
+            List::User => self.next_user_visit,
+            List::Location => self.next_location_visit,
+        }
+    }
+}
+
+struct LocationRecord {
+    distance: u32,
+    country: u32,
+    city: u32,
+    place: u32,
+    first_visit: u32,
+}
+
+const TABLES: [&str; 3] = ["users", "visits", "locations"];
+
+const DICTS: [&str; 6] = [
+    "emails",
+    "first_names",
+    "last_names",
+    "countries",
+    "cities",
+    "places",
+];
+
+fn remove_files(dir: &Path) -> io::Result<()> {
+    let mut files = Vec::new();
+    for name in TABLES {
+        files.push(format!("{}.bin", name));
+        files.push(format!("{}.ids", name));
+    }
+    for name in DICTS {
+        files.push(format!("{}.idx", name));
+        files.push(format!("{}.heap", name));
+        files.push(format!("{}.table", name));
+    }
+
+    for f in files {
+        match fs::remove_file(dir.join(f)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+fn read_u32(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(b[off..off + 4].try_into().unwrap())
+}
+
+fn read_i32(b: &[u8], off: usize) -> i32 {
+    i32::from_le_bytes(b[off..off + 4].try_into().unwrap())
+}
+
+fn read_meta(path: &Path) -> io::Result<Meta> {
+    let b = match fs::read(path) {
+        Ok(b) => b,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(Meta {
+                timestamp: 0,
+                last_seq: 0,
+                complete: false,
+            })
+        }
+        Err(e) => return Err(e),
+    };
+
+    if b.len() < 8 || &b[0..4] != META_MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid meta file",
+        ));
+    }
+
+    let version = u32::from_le_bytes(b[4..8].try_into().unwrap());
+    match (version, b.len()) {
+        // versions 1 and 2 had the visit indexes in the memory,
+        // their files are replaced by loading the dataset again
+        (1, 17) | (2, 25) => {
+            warn!("mmap: storage version {} is outdated, the data is loaded again", version);
+            Ok(Meta {
+                timestamp: 0,
+                last_seq: 0,
+                complete: false,
+            })
+        }
+        (META_VERSION, 25) => Ok(Meta {
+            timestamp: i64::from_le_bytes(b[8..16].try_into().unwrap()),
+            last_seq: u64::from_le_bytes(b[16..24].try_into().unwrap()),
+            complete: b[24] == 1,
+        }),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported storage version {}", version),
+        )),
+    }
+}
+
+fn write_meta(path: &Path, meta: &Meta) -> io::Result<()> {
+    let mut b = Vec::with_capacity(25);
+    b.extend_from_slice(META_MAGIC);
+    b.extend_from_slice(&META_VERSION.to_le_bytes());
+    b.extend_from_slice(&meta.timestamp.to_le_bytes());
+    b.extend_from_slice(&meta.last_seq.to_le_bytes());
+    b.push(meta.complete as u8);
+
+    // written to a temporary file first so a crash can't leave a torn meta
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, b)?;
+    File::open(&tmp)?.sync_all()?;
+    fs::rename(tmp, path)
+}
+
+// memory-mapped file growing on demand,
+// the first HEADER bytes keep the length of the used space
+struct MmapFile {
+    file: File,
+    map: MmapMut,
+}
+
+impl MmapFile {
+    fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        if file.metadata()?.len() < MIN_FILE_SIZE {
+            file.set_len(MIN_FILE_SIZE)?;
+        }
+
+        // safety: the file is owned by the storage and is not modified by other processes
+        let map = unsafe { MmapMut::map_mut(&file)? };
+
+        Ok(MmapFile { file, map })
+    }
+
+    // length of the used space without the header
+    fn len(&self) -> u64 {
+        u64::from_le_bytes(self.map[0..8].try_into().unwrap())
+    }
+
+    fn set_len(&mut self, len: u64) {
+        self.map[0..8].copy_from_slice(&len.to_le_bytes());
+    }
+
+    fn bytes(&self, off: u64, len: u64) -> &[u8] {
+        let start = (HEADER + off) as usize;
+        &self.map[start..start + len as usize]
+    }
+
+    // grows the file, so len bytes of the used space fit into the mapping
+    fn reserve(&mut self, len: u64) -> io::Result<()> {
+        let needed = HEADER + len;
+        if needed <= self.map.len() as u64 {
+            return Ok(());
+        }
+
+        let size = needed.max(self.map.len() as u64 * 2);
+        self.map.flush()?;
+        self.file.set_len(size)?;
+        self.map = unsafe { MmapMut::map_mut(&self.file)? };
+
+        Ok(())
+    }
+
+    fn write_at(&mut self, off: u64, data: &[u8]) -> io::Result<()> {
+        let end = off + data.len() as u64;
+        self.reserve(end)?;
+
+        let start = (HEADER + off) as usize;
+        self.map[start..start + data.len()].copy_from_slice(data);
+
+        if end > self.len() {
+            self.set_len(end);
+        }
+
+        Ok(())
+    }
+
+    fn append(&mut self, data: &[u8]) -> io::Result<u64> {
+        let off = self.len();
+        self.write_at(off, data)?;
+        Ok(off)
+    }
+
+    // inserts the data at the offset of the used space, moving the rest after it
+    fn insert_at(&mut self, off: u64, data: &[u8]) -> io::Result<()> {
+        let len = self.len();
+        self.reserve(len + data.len() as u64)?;
+
+        let start = (HEADER + off) as usize;
+        let end = (HEADER + len) as usize;
+        self.map.copy_within(start..end, start + data.len());
+        self.map[start..start + data.len()].copy_from_slice(data);
+        self.set_len(len + data.len() as u64);
+
+        Ok(())
+    }
+
+    // makes the used space len zero bytes
+    fn zero(&mut self, len: u64) -> io::Result<()> {
+        self.reserve(len)?;
+        self.map[HEADER as usize..(HEADER + len) as usize].fill(0);
+        self.set_len(len);
+
+        Ok(())
+    }
+}
+
+// fixed-size records of the entities indexed by id in one file,
+// and the sorted ids of the present records in another one
+struct Table {
+    records: MmapFile,
+    ids: MmapFile,
+    record_size: u64,
+}
+
+impl Table {
+    fn open(dir: &Path, name: &str, record_size: u64) -> io::Result<Self> {
+        Ok(Table {
+            records: MmapFile::open(&dir.join(format!("{}.bin", name)))?,
+            ids: MmapFile::open(&dir.join(format!("{}.ids", name)))?,
+            record_size,
+        })
+    }
+
+    // number of the stored records
+    fn len(&self) -> usize {
+        (self.ids.len() / 4) as usize
+    }
+
+    fn id_at(&self, idx: usize) -> u32 {
+        read_u32(self.ids.bytes(idx as u64 * 4, 4), 0)
+    }
+
+    // ids of the stored records in ascending order
+    fn ids(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..self.len()).map(|idx| self.id_at(idx))
+    }
+
+    // record of the id if it's present, the first byte of a record is the presence flag
+    fn record(&self, id: u32) -> Option<&[u8]> {
+        let off = id as u64 * self.record_size;
+        if off + self.record_size > self.records.len() {
+            return None;
+        }
+
+        let b = self.records.bytes(off, self.record_size);
+        if b[0] == 0 {
+            return None;
+        }
+
+        Some(b)
+    }
+
+    fn contains(&self, id: u32) -> bool {
+        self.record(id).is_some()
+    }
+
+    fn reserve(&mut self, count: usize) -> io::Result<()> {
+        self.records.reserve(count as u64 * self.record_size)
+    }
+
+    // writes the new record and adds its id, the ids may be appended
+    // unsorted in the bulk load and sorted by sort_ids at its end
+    fn insert(&mut self, id: u32, data: &[u8], sorted: bool) -> io::Result<()> {
+        self.records.write_at(id as u64 * self.record_size, data)?;
+
+        let res = if sorted {
+            self.insert_id(id)
+        } else {
+            self.ids.append(&id.to_le_bytes()).map(|_| ())
+        };
+        // the record without the id would be missing from the ids
+        if let Err(e) = res {
+            self.clear(id)?;
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    fn insert_id(&mut self, id: u32) -> io::Result<()> {
+        let len = self.len();
+
+        // the new ids are usually greater than all the stored ones
+        let idx = if len == 0 || self.id_at(len - 1) < id {
+            len
+        } else {
+            let (mut lo, mut hi) = (0, len);
+            while lo < hi {
+                let mid = (lo + hi) / 2;
+                if self.id_at(mid) < id {
+                    lo = mid + 1;
+                } else {
+                    hi = mid;
+                }
+            }
+            lo
+        };
+
+        self.ids.insert_at(idx as u64 * 4, &id.to_le_bytes())
+    }
+
+    // clears the presence flag of the record, sort_ids drops its id
+    fn clear(&mut self, id: u32) -> io::Result<()> {
+        self.records.write_at(id as u64 * self.record_size, &[0])
+    }
+
+    fn sort_ids(&mut self) -> io::Result<()> {
+        let mut ids: Vec<u32> = self.ids().filter(|id| self.contains(*id)).collect();
+        ids.sort_unstable();
+
+        let b: Vec<u8> = ids.iter().flat_map(|id| id.to_le_bytes()).collect();
+        self.ids.set_len(0);
+        self.ids.write_at(0, &b)
+    }
+
+    fn write_u32(&mut self, id: u32, field: u64, value: u32) -> io::Result<()> {
+        self.records
+            .write_at(id as u64 * self.record_size + field, &value.to_le_bytes())
+    }
+}
+
+// dictionary persisted in three files: the strings heap, the index of
+// (offset, length) entries into the heap and the open addressing hash table
+// of the entries, kept at most half full. the slots of the table are
+// entry id + 1, zero is an empty slot
+struct MmapDict {
+    idx: MmapFile,
+    heap: MmapFile,
+    table: MmapFile,
+}
+
+const DICT_ENTRY: u64 = 12;
+const MIN_SLOTS: u64 = 1024;
+
+impl MmapDict {
+    fn open(dir: &Path, name: &str) -> io::Result<Self> {
+        Ok(MmapDict {
+            idx: MmapFile::open(&dir.join(format!("{}.idx", name)))?,
+            heap: MmapFile::open(&dir.join(format!("{}.heap", name)))?,
+            table: MmapFile::open(&dir.join(format!("{}.table", name)))?,
+        })
+    }
+
+    // number of the strings
+    fn len(&self) -> usize {
+        (self.idx.len() / DICT_ENTRY) as usize
+    }
+
+    fn get(&self, id: u32) -> &str {
+        let entry = self.idx.bytes(id as u64 * DICT_ENTRY, DICT_ENTRY);
+        let off = u64::from_le_bytes(entry[0..8].try_into().unwrap());
+        let len = read_u32(entry, 8);
+
+        // only valid strings are written to the heap
+        str::from_utf8(self.heap.bytes(off, len as u64)).unwrap_or_default()
+    }
+
+    fn slots(&self) -> u64 {
+        self.table.len() / 4
+    }
+
+    fn slot(&self, slot: u64) -> u32 {
+        read_u32(self.table.bytes(slot * 4, 4), 0)
+    }
+
+    // id of the string if it's in the dictionary
+    fn find(&self, s: &str) -> Option<u32> {
+        let slots = self.slots();
+        if slots == 0 {
+            return None;
+        }
+
+        let mut slot = hash(s) % slots;
+        loop {
+            match self.slot(slot) {
+                0 => return None,
+                v if self.get(v - 1) == s => return Some(v - 1),
+                _ => slot = (slot + 1) % slots,
+            }
+        }
+    }
+
+    // returns id of the string, appends it to the dictionary if it doesn't exist
+    fn put(&mut self, s: &str) -> io::Result<u32> {
+        if let Some(id) = self.find(s) {
+            return Ok(id);
+        }
+
+        if (self.len() as u64 + 1) * 2 > self.slots() {
+            self.grow()?;
+        }
+
+        let off = self.heap.append(s.as_bytes())?;
+
+        let mut entry = [0u8; DICT_ENTRY as usize];
+        entry[0..8].copy_from_slice(&off.to_le_bytes());
+        entry[8..12].copy_from_slice(&(s.len() as u32).to_le_bytes());
+        self.idx.append(&entry)?;
+
+        let id = self.len() as u32 - 1;
+        self.place(id)?;
+
+        Ok(id)
+    }
+
+    fn place(&mut self, id: u32) -> io::Result<()> {
+        let slots = self.slots();
+
+        let mut slot = hash(self.get(id)) % slots;
+        while self.slot(slot) != 0 {
+            slot = (slot + 1) % slots;
+        }
+
+        self.table.write_at(slot * 4, &(id + 1).to_le_bytes())
+    }
+
+    // doubles the hash table and places all the entries again
+    fn grow(&mut self) -> io::Result<()> {
+        let slots = (self.slots() * 2).max(MIN_SLOTS);
+        self.table.zero(slots * 4)?;
+
+        for id in 0..self.len() as u32 {
+            self.place(id)?;
+        }
+
+        Ok(())
+    }
+}
+
+// FNV-1a, the hashes are persisted so they must not depend on the build
+fn hash(s: &str) -> u64 {
+    s.bytes().fold(0xcbf29ce484222325, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemStorage;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hlcup-mmap-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn user(id: u32) -> model::UserJSON {
+        model::UserJSON {
+            id,
+            email: format!("user{}@mail.ru", id),
+            first_name: format!("Name{}", id % 7),
+            last_name: "Li".to_string(),
+            gender: if id.is_multiple_of(2) { "m" } else { "f" }.to_string(),
+            birth_date: -((id % 100) as i32) * 10_000_000,
+        }
+    }
+
+    fn location(id: u32) -> model::LocationJSON {
+        model::LocationJSON {
+            id,
+            distance: id % 100 * 3,
+            city: "Paris".to_string(),
+            country: format!("Country{}", id % 3),
+            place: format!("Place{}", id),
+        }
+    }
+
+    // the visits are spread over the users and locations, some of them
+    // have the same visited_at
+    fn visit(id: u32) -> model::VisitJSON {
+        model::VisitJSON {
+            id,
+            user: id % 5 + 1,
+            location: id % 4 + 1,
+            mark: (id % 6) as u8,
+            visited_at: (id * 7919 % 23) as i32 * 1000,
+        }
+    }
+
+    fn fill(s: &mut dyn Storage, bulk: bool) {
+        s.set_timestamp(1_500_000_000);
+        if bulk {
+            s.begin_bulk_load();
+            // the visits come first in the bulk load
+            for id in 1..=40 {
+                s.store_visit(&visit(id)).unwrap();
+            }
+        }
+        for id in 1..=5 {
+            s.store_user(&user(id)).unwrap();
+        }
+        for id in 1..=4 {
+            s.store_location(&location(id)).unwrap();
+        }
+        if bulk {
+            assert!(s.end_bulk_load(1).is_empty());
+        } else {
+            for id in 1..=40 {
+                s.store_visit(&visit(id)).unwrap();
+            }
+        }
+    }
+
+    // the responses of all the queries of the storage
+    fn queries(s: &dyn Storage) -> Vec<String> {
+        let mut out = Vec::new();
+
+        for id in 1..=5 {
+            out.push(serde_json::to_string(&s.user(id)).unwrap());
+            for (from_date, to_date, country) in [
+                (None, None, None),
+                (Some(3000), Some(15000), None),
+                (None, None, Some("Country1")),
+            ] {
+                let params = model::UserVisitsParams {
+                    from_date,
+                    to_date,
+                    country: country.map(String::from),
+                    to_distance: None,
+                };
+                out.push(serde_json::to_string(&s.user_visits(id, &params).unwrap()).unwrap());
+            }
+        }
+        for id in 1..=4 {
+            out.push(serde_json::to_string(&s.location(id)).unwrap());
+            for (from_date, gender) in [(None, None), (Some(5000), Some("f"))] {
+                let params = model::LocationAvgParams {
+                    from_date,
+                    to_date: None,
+                    from_age: None,
+                    to_age: None,
+                    gender: gender.map(String::from),
+                };
+                out.push(s.location_avg(id, &params).unwrap().to_string());
+            }
+        }
+
+        out
+    }
+
+    #[test]
+    fn answers_as_the_memory_storage() {
+        let mut mem = MemStorage::new();
+        fill(&mut mem, false);
+
+        for bulk in [false, true] {
+            let dir = dir(&format!("queries-{}", bulk));
+            let mut s = MmapStorage::open(&dir).unwrap();
+            fill(&mut s, bulk);
+
+            assert_eq!(queries(&s), queries(&mem));
+            assert_eq!(s.visit_ids().collect::<Vec<_>>(), (1..=40).collect::<Vec<_>>());
+
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn reopens_without_rebuilding() {
+        let dir = dir("reopen");
+
+        let mut s = MmapStorage::open(&dir).unwrap();
+        fill(&mut s, true);
+        s.set_last_seq(7);
+        s.mark_complete().unwrap();
+        let before = queries(&s);
+        drop(s);
+
+        let mut s = MmapStorage::open(&dir).unwrap();
+        assert!(s.is_complete());
+        assert_eq!(s.last_seq(), 7);
+        assert_eq!(queries(&s), before);
+        assert_eq!(s.counts().visits, 40);
+
+        // the stored lists and ids keep their order with new entities
+        s.store_user(&user(0)).unwrap();
+        s.store_visit(&model::VisitJSON { id: 0, user: 0, ..visit(1) }).unwrap();
+        assert_eq!(s.user_ids().collect::<Vec<_>>(), vec![0, 1, 2, 3, 4, 5]);
+        assert!(s.has_email("user0@mail.ru"));
+        assert_eq!(s.store_user(&user(0)), Err(StoreError::UserExists));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn drops_the_changes_not_flushed() {
+        let dir = dir("changes");
+
+        let mut s = MmapStorage::open(&dir).unwrap();
+        fill(&mut s, true);
+        s.mark_complete().unwrap();
+
+        // the flushed change is kept
+        s.store_user(&user(0)).unwrap();
+        s.set_last_seq(1);
+        s.flush().unwrap();
+        drop(s);
+
+        let mut s = MmapStorage::open(&dir).unwrap();
+        assert!(s.is_complete());
+        assert_eq!(s.last_seq(), 1);
+        assert!(s.user(0).is_some());
+
+        // a crash after a change, the dataset is loaded again with the whole log
+        s.store_location(&location(0)).unwrap();
+        drop(s);
+
+        let s = MmapStorage::open(&dir).unwrap();
+        assert!(!s.is_complete());
+        assert_eq!(s.counts().users, 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn drops_the_orphan_visits_of_the_bulk_load() {
+        let dir = dir("orphans");
+        let mut s = MmapStorage::open(&dir).unwrap();
+
+        s.begin_bulk_load();
+        s.store_user(&user(1)).unwrap();
+        s.store_location(&location(1)).unwrap();
+        s.store_visit(&model::VisitJSON { id: 3, user: 1, location: 1, ..visit(3) }).unwrap();
+        s.store_visit(&model::VisitJSON { id: 1, user: 9, location: 1, ..visit(1) }).unwrap();
+        s.store_visit(&model::VisitJSON { id: 2, user: 1, location: 9, ..visit(2) }).unwrap();
+
+        let orphans = s.end_bulk_load(1);
+        assert_eq!(
+            orphans,
+            vec![(1, StoreError::UserNotFound), (2, StoreError::LocationNotFound)]
+        );
+        assert_eq!(s.visit_ids().collect::<Vec<_>>(), vec![3]);
+        assert!(s.visit(1).is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_ids_out_of_range() {
+        let dir = dir("range");
+        let mut s = MmapStorage::open(&dir).unwrap();
+
+        assert_eq!(s.store_user(&user(MAX_ID + 1)), Err(StoreError::IdOutOfRange));
+        assert_eq!(s.store_location(&location(u32::MAX)), Err(StoreError::IdOutOfRange));
+        assert!(s.store_user(&user(MAX_ID)).is_ok());
+        assert_eq!(s.user_ids().collect::<Vec<_>>(), vec![MAX_ID]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn grows_the_dictionary_table() {
+        let dir = dir("dict");
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut d = MmapDict::open(&dir, "names").unwrap();
+        for i in 0..3000 {
+            assert_eq!(d.put(&format!("name{}", i)).unwrap(), i);
+        }
+        assert_eq!(d.put("name17").unwrap(), 17);
+        drop(d);
+
+        let d = MmapDict::open(&dir, "names").unwrap();
+        assert_eq!(d.len(), 3000);
+        assert!(d.slots() >= 6000);
+        assert_eq!(d.find("name2999"), Some(2999));
+        assert_eq!(d.find("name3000"), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
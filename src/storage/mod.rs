@@ -0,0 +1,163 @@
+pub mod memory;
+pub mod mmap;
+pub mod snapshot;
+
+use std::{fmt, io};
+
+use serde::Serialize;
+
+use crate::model;
+
+pub use memory::MemStorage;
+pub use mmap::MmapStorage;
+
+// storage backend of the entities,
+// handlers work only through this trait so backends can be swapped
+pub trait Storage: Send + Sync {
+    // current time of the dataset, ages of users are counted from it
+    fn timestamp(&self) -> i64;
+    fn set_timestamp(&mut self, timestamp: i64);
+
+    // sequence number of the last mutation from the log applied to the storage
+    fn last_seq(&self) -> u64;
+    fn set_last_seq(&mut self, seq: u64);
+
+    fn user(&self, id: u32) -> Option<model::UserRef<'_>>;
+    fn location(&self, id: u32) -> Option<model::LocationRef<'_>>;
+    fn visit(&self, id: u32) -> Option<model::VisitJSON>;
+
+    // whether a stored user has the email
+    fn has_email(&self, email: &str) -> bool;
+
+    // visits of the user sorted by visited_at and filtered by params
+    fn user_visits(
+        &self,
+        id: u32,
+        params: &model::UserVisitsParams,
+    ) -> Result<Vec<model::UserVisitRef<'_>>, QueryError>;
+
+    // average mark of the location visits filtered by params
+    fn location_avg(&self, id: u32, params: &model::LocationAvgParams) -> Result<f64, QueryError>;
+
+    // capacity hint before a bulk load, counts are upper bounds of the ids
+    fn reserve(&mut self, _counts: Counts) {}
+
+    fn store_user(&mut self, user: &model::UserJSON) -> Result<(), StoreError>;
+    fn store_location(&mut self, location: &model::LocationJSON) -> Result<(), StoreError>;
+    fn store_visit(&mut self, visit: &model::VisitJSON) -> Result<(), StoreError>;
+
+    // in the bulk load mode the visits are stored without the visit indexes
+    // and may come before their users and locations. end_bulk_load builds
+    // and sorts all the indexes using the given number of threads, the visits
+    // of the users or locations which haven't been stored are dropped.
+    // returns the ids of the dropped visits with the reasons
+    fn begin_bulk_load(&mut self) {}
+    fn end_bulk_load(&mut self, _threads: usize) -> Vec<(u32, StoreError)> {
+        Vec::new()
+    }
+
+    // ids of the stored entities in ascending order
+    fn user_ids(&self) -> Box<dyn Iterator<Item = u32> + '_>;
+    fn location_ids(&self) -> Box<dyn Iterator<Item = u32> + '_>;
+    fn visit_ids(&self) -> Box<dyn Iterator<Item = u32> + '_>;
+
+    fn counts(&self) -> Counts;
+
+    // numbers of the entries of the string dictionaries by their names
+    fn dict_sizes(&self) -> Vec<(&'static str, usize)> {
+        Vec::new()
+    }
+
+    // persists the pending changes for the backends backed by files
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    // writes the binary snapshot of the whole storage, see snapshot.rs
+    fn write_snapshot(&self, _w: &mut dyn io::Write) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "snapshots are not supported by the storage",
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Counts {
+    pub users: usize,
+    pub visits: usize,
+    pub locations: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum QueryError {
+    NotFound,
+    BadRequest,
+}
+
+// reasons of rejected mutations
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StoreError {
+    UserExists,
+    LocationExists,
+    VisitExists,
+    EmailExists,
+    UnknownGender,
+    UserNotFound,
+    LocationNotFound,
+    // the id is beyond the ids supported by the backend
+    IdOutOfRange,
+    // the backend failed to write the entity
+    Io,
+    // the server is a follower and doesn't accept writes
+    ReadOnly,
+}
+
+// sorts every visit index by visited_at, the indexes are split between the threads.
+// the indexes are filled in the order of visit ids, so the visits with the same
+// visited_at end up as with the sorted insertion one by one: the later goes first
+pub(crate) fn sort_visit_indexes<T: Send>(
+    mut indexes: Vec<&mut Vec<T>>,
+    threads: usize,
+    visited_at: fn(&T) -> i32,
+) {
+    let chunk = indexes.len().div_ceil(threads.max(1)).max(1);
+
+    std::thread::scope(|scope| {
+        for part in indexes.chunks_mut(chunk) {
+            scope.spawn(move || {
+                for visits in part {
+                    visits.reverse();
+                    visits.sort_by_key(visited_at);
+                }
+            });
+        }
+    });
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            StoreError::UserExists => "user is already exist",
+            StoreError::LocationExists => "location is already exist",
+            StoreError::VisitExists => "visit is already exist",
+            StoreError::EmailExists => "email is already exist",
+            StoreError::UnknownGender => "gender is unknown",
+            StoreError::UserNotFound => "user is not found",
+            StoreError::LocationNotFound => "location is not found",
+            StoreError::IdOutOfRange => "id is out of range",
+            StoreError::Io => "storage write error",
+            StoreError::ReadOnly => "storage is read-only",
+        };
+        f.write_str(s)
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<io::Error> for StoreError {
+    fn from(_: io::Error) -> Self {
+        StoreError::Io
+    }
+}
@@ -0,0 +1,523 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use chrono::NaiveDateTime;
+
+use crate::{dict::Dict, model};
+
+use super::{MemStorage, Storage};
+
+// binary snapshot of the in-memory storage.
+// layout: magic, version u32, then the body and crc32 of the body.
+// body: timestamp, last log sequence number, the dictionaries,
+// users, locations (both with their sorted visit indexes) and visits.
+// all the numbers are little-endian, strings are length-prefixed
+const MAGIC: &[u8; 4] = b"HLSN";
+const VERSION: u32 = 1;
+
+const FILE_PREFIX: &str = "snapshot-";
+const FILE_EXT: &str = "bin";
+
+pub fn write(s: &MemStorage, w: &mut dyn Write) -> io::Result<()> {
+    w.write_all(MAGIC)?;
+    w.write_all(&VERSION.to_le_bytes())?;
+
+    let mut e = Encoder {
+        w,
+        crc: crc32fast::Hasher::new(),
+    };
+
+    e.i64(s.timestamp)?;
+    e.u64(s.last_seq)?;
+
+    for dict in [
+        &s.first_names,
+        &s.last_names,
+        &s.countries,
+        &s.cities,
+        &s.places,
+    ] {
+        e.u64(dict.entries().len() as u64)?;
+        for entry in dict.entries() {
+            e.str(entry)?;
+        }
+    }
+
+    e.u64(s.users.len() as u64)?;
+    for (id, user) in s.users.iter() {
+        e.u32(id)?;
+        e.str(&user.email)?;
+        e.u32(user.first_name)?;
+        e.u32(user.last_name)?;
+        e.i32(user.birth_date)?;
+        e.u8(user.age)?;
+        e.u8(user.gender.code())?;
+
+        e.u64(user.visits.len() as u64)?;
+        for visit in &user.visits {
+            e.u32(visit.id)?;
+            e.u32(visit.location)?;
+            e.i32(visit.visited_at)?;
+        }
+    }
+
+    e.u64(s.locations.len() as u64)?;
+    for (id, location) in s.locations.iter() {
+        e.u32(id)?;
+        e.u32(location.country)?;
+        e.u32(location.city)?;
+        e.u32(location.place)?;
+        e.u32(location.distance)?;
+
+        e.u64(location.visits.len() as u64)?;
+        for visit in &location.visits {
+            e.u32(visit.visit_id)?;
+            e.i32(visit.visited_at)?;
+        }
+    }
+
+    e.u64(s.visits.len() as u64)?;
+    for (id, visit) in s.visits.iter() {
+        e.u32(id)?;
+        e.u32(visit.user)?;
+        e.u32(visit.location)?;
+        e.u8(visit.mark)?;
+        e.i32(visit.visited_at)?;
+    }
+
+    let crc = e.crc.finalize();
+    e.w.write_all(&crc.to_le_bytes())
+}
+
+pub fn read(r: &mut dyn Read) -> io::Result<MemStorage> {
+    let mut header = [0u8; 8];
+    r.read_exact(&mut header)?;
+
+    if &header[0..4] != MAGIC {
+        return Err(invalid("not a snapshot"));
+    }
+    let version = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    if version != VERSION {
+        return Err(invalid(&format!("unsupported snapshot version {}", version)));
+    }
+
+    let mut d = Decoder {
+        r,
+        crc: crc32fast::Hasher::new(),
+    };
+
+    let mut s = MemStorage::new();
+
+    s.timestamp = d.i64()?;
+    s.last_seq = d.u64()?;
+
+    // the ages of the new users are counted from it
+    if NaiveDateTime::from_timestamp_opt(s.timestamp, 0).is_none() {
+        return Err(invalid("invalid timestamp in snapshot"));
+    }
+
+    let mut dicts = Vec::with_capacity(5);
+    for _ in 0..5 {
+        let len = d.u64()?;
+        let mut entries = Vec::with_capacity(capacity(len));
+        for _ in 0..len {
+            entries.push(d.str()?);
+        }
+        dicts.push(Dict::from_entries(entries));
+    }
+    s.places = dicts.pop().unwrap();
+    s.cities = dicts.pop().unwrap();
+    s.countries = dicts.pop().unwrap();
+    s.last_names = dicts.pop().unwrap();
+    s.first_names = dicts.pop().unwrap();
+
+    let users = d.u64()?;
+    for _ in 0..users {
+        let id = d.u32()?;
+        let email = d.str()?;
+        let first_name = d.u32()?;
+        let last_name = d.u32()?;
+        let birth_date = d.i32()?;
+        let age = d.u8()?;
+        let gender = model::Gender::from_code(d.u8()?);
+
+        let len = d.u64()?;
+        let mut visits = Vec::with_capacity(capacity(len));
+        for _ in 0..len {
+            visits.push(model::UserVisit {
+                id: d.u32()?,
+                location: d.u32()?,
+                visited_at: d.i32()?,
+            });
+        }
+
+        s.emails.insert(email.clone());
+        s.users.insert(
+            id,
+            model::User {
+                email,
+                first_name,
+                last_name,
+                birth_date,
+                age,
+                gender,
+                visits,
+            },
+        );
+    }
+
+    let locations = d.u64()?;
+    for _ in 0..locations {
+        let id = d.u32()?;
+        let country = d.u32()?;
+        let city = d.u32()?;
+        let place = d.u32()?;
+        let distance = d.u32()?;
+
+        let len = d.u64()?;
+        let mut visits = Vec::with_capacity(capacity(len));
+        for _ in 0..len {
+            visits.push(model::LocationVisit {
+                visit_id: d.u32()?,
+                visited_at: d.i32()?,
+            });
+        }
+
+        s.locations.insert(
+            id,
+            model::Location {
+                country,
+                city,
+                place,
+                distance,
+                visits,
+            },
+        );
+    }
+
+    let visits = d.u64()?;
+    for _ in 0..visits {
+        let id = d.u32()?;
+        s.visits.insert(
+            id,
+            model::Visit {
+                user: d.u32()?,
+                location: d.u32()?,
+                mark: d.u8()?,
+                visited_at: d.i32()?,
+            },
+        );
+    }
+
+    let crc = d.crc.finalize();
+    let mut expected = [0u8; 4];
+    d.r.read_exact(&mut expected)?;
+    if crc != u32::from_le_bytes(expected) {
+        return Err(invalid("snapshot checksum mismatch"));
+    }
+
+    Ok(s)
+}
+
+// writes the snapshot of the storage to the directory,
+// keeps only the newest `keep` snapshots there, the written one is always kept.
+// returns the path of the written snapshot
+pub fn save(s: &dyn Storage, dir: &Path, keep: usize) -> io::Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+
+    let path = dir.join(format!("{}{:020}.{}", FILE_PREFIX, s.last_seq(), FILE_EXT));
+
+    // written to a temporary file first, so a crash can't leave a torn snapshot
+    let tmp = path.with_extension("tmp");
+    let file = File::create(&tmp)?;
+    let mut w = BufWriter::new(file);
+    s.write_snapshot(&mut w)?;
+    let file = w.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    fs::rename(&tmp, &path)?;
+
+    let snapshots = list(dir)?;
+    let keep = keep.max(1);
+    if snapshots.len() > keep {
+        for (_, old) in &snapshots[..snapshots.len() - keep] {
+            if *old != path {
+                fs::remove_file(old)?;
+            }
+        }
+    }
+
+    Ok(path)
+}
+
+// snapshots in the directory with their log sequence numbers, the oldest first
+pub fn list(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut snapshots = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some(FILE_EXT) {
+            continue;
+        }
+
+        let seq = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.strip_prefix(FILE_PREFIX))
+            .and_then(|seq| seq.parse().ok());
+
+        if let Some(seq) = seq {
+            snapshots.push((seq, path));
+        }
+    }
+    snapshots.sort();
+
+    Ok(snapshots)
+}
+
+// loads the newest readable snapshot from the directory,
+// skipping the snapshots with the log sequence number above max_seq
+pub fn load_latest(dir: &Path, max_seq: u64) -> io::Result<Option<(MemStorage, PathBuf)>> {
+    for (seq, path) in list(dir)?.into_iter().rev() {
+        if seq > max_seq {
+            continue;
+        }
+
+        match load(&path) {
+            Ok(s) => return Ok(Some((s, path))),
+            Err(e) => warn!("snapshot {}: {}", path.display(), e),
+        }
+    }
+
+    Ok(None)
+}
+
+pub fn load(path: &Path) -> io::Result<MemStorage> {
+    let mut r = BufReader::new(File::open(path)?);
+    read(&mut r)
+}
+
+// preallocation is limited, so a corrupted length fails on read instead of allocation
+fn capacity(len: u64) -> usize {
+    len.min(1 << 20) as usize
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+struct Encoder<'a> {
+    w: &'a mut dyn Write,
+    crc: crc32fast::Hasher,
+}
+
+impl Encoder<'_> {
+    fn bytes(&mut self, b: &[u8]) -> io::Result<()> {
+        self.crc.update(b);
+        self.w.write_all(b)
+    }
+
+    fn u8(&mut self, v: u8) -> io::Result<()> {
+        self.bytes(&[v])
+    }
+
+    fn u32(&mut self, v: u32) -> io::Result<()> {
+        self.bytes(&v.to_le_bytes())
+    }
+
+    fn i32(&mut self, v: i32) -> io::Result<()> {
+        self.bytes(&v.to_le_bytes())
+    }
+
+    fn u64(&mut self, v: u64) -> io::Result<()> {
+        self.bytes(&v.to_le_bytes())
+    }
+
+    fn i64(&mut self, v: i64) -> io::Result<()> {
+        self.bytes(&v.to_le_bytes())
+    }
+
+    fn str(&mut self, s: &str) -> io::Result<()> {
+        self.u32(s.len() as u32)?;
+        self.bytes(s.as_bytes())
+    }
+}
+
+struct Decoder<'a> {
+    r: &'a mut dyn Read,
+    crc: crc32fast::Hasher,
+}
+
+impl Decoder<'_> {
+    fn bytes<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut b = [0u8; N];
+        self.r.read_exact(&mut b)?;
+        self.crc.update(&b);
+        Ok(b)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes::<1>()?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes()?))
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        Ok(i32::from_le_bytes(self.bytes()?))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes()?))
+    }
+
+    fn i64(&mut self) -> io::Result<i64> {
+        Ok(i64::from_le_bytes(self.bytes()?))
+    }
+
+    // the length isn't trusted before the checksum is verified,
+    // so the string is read up to it instead of allocated for it
+    fn str(&mut self) -> io::Result<String> {
+        let len = self.u32()? as u64;
+        let mut b = Vec::with_capacity(capacity(len));
+        (&mut *self.r).take(len).read_to_end(&mut b)?;
+        if (b.len() as u64) < len {
+            return Err(invalid("truncated string in snapshot"));
+        }
+        self.crc.update(&b);
+        String::from_utf8(b).map_err(|_| invalid("invalid string in snapshot"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage() -> MemStorage {
+        let mut s = MemStorage::new();
+        s.set_timestamp(1_500_000_000);
+        s.set_last_seq(42);
+
+        for id in [1, 2, 70_000_000] {
+            s.store_user(&model::UserJSON {
+                id,
+                email: format!("user{}@mail.ru", id),
+                first_name: "Ann".to_string(),
+                last_name: format!("Li{}", id % 2),
+                gender: "f".to_string(),
+                birth_date: -100_000_000,
+            })
+            .unwrap();
+        }
+        s.store_location(&model::LocationJSON {
+            id: 5,
+            distance: 12,
+            city: "Paris".to_string(),
+            country: "France".to_string(),
+            place: "Louvre".to_string(),
+        })
+        .unwrap();
+        for (id, user, visited_at) in [(1, 1, 300), (2, 1, 100), (3, 70_000_000, 200)] {
+            s.store_visit(&model::VisitJSON {
+                id,
+                user,
+                location: 5,
+                mark: 4,
+                visited_at,
+            })
+            .unwrap();
+        }
+
+        s
+    }
+
+    fn snapshot(s: &MemStorage) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write(s, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn reads_the_written_snapshot() {
+        let s = storage();
+        let read = read(&mut &snapshot(&s)[..]).unwrap();
+
+        assert_eq!(read.timestamp(), 1_500_000_000);
+        assert_eq!(read.last_seq(), 42);
+        assert!(read.has_email("user2@mail.ru"));
+        assert_eq!(read.user_ids().collect::<Vec<_>>(), vec![1, 2, 70_000_000]);
+        assert_eq!(read.visit_ids().collect::<Vec<_>>(), vec![1, 2, 3]);
+
+        for id in [1, 2, 70_000_000] {
+            assert_eq!(
+                serde_json::to_string(&read.user(id)).unwrap(),
+                serde_json::to_string(&s.user(id)).unwrap()
+            );
+        }
+        let params = model::UserVisitsParams {
+            from_date: None,
+            to_date: None,
+            country: Some("France".to_string()),
+            to_distance: None,
+        };
+        assert_eq!(
+            serde_json::to_string(&read.user_visits(1, &params).unwrap()).unwrap(),
+            serde_json::to_string(&s.user_visits(1, &params).unwrap()).unwrap()
+        );
+        assert_eq!(serde_json::to_string(&read.location(5)).unwrap(), serde_json::to_string(&s.location(5)).unwrap());
+
+        // the snapshot of the read storage is the same
+        assert_eq!(snapshot(&read), snapshot(&s));
+    }
+
+    #[test]
+    fn rejects_corrupted_snapshots() {
+        let data = snapshot(&storage());
+
+        let mut corrupted = data.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert_eq!(read(&mut &corrupted[..]).err().unwrap().to_string(), "snapshot checksum mismatch");
+
+        // the length of the first string, the first name, isn't allocated
+        let mut corrupted = data.clone();
+        corrupted[32..36].copy_from_slice(&u32::MAX.to_le_bytes());
+        let err = read(&mut &corrupted[..]).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut corrupted = data.clone();
+        corrupted[8..16].copy_from_slice(&i64::MAX.to_le_bytes());
+        assert_eq!(read(&mut &corrupted[..]).err().unwrap().to_string(), "invalid timestamp in snapshot");
+
+        assert!(read(&mut &data[..data.len() - 10]).is_err());
+        assert_eq!(read(&mut &b"HLSN\x02\0\0\0"[..]).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn keeps_the_newest_snapshots() {
+        let dir = std::env::temp_dir().join(format!("hlcup-snapshots-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut s = storage();
+
+        for seq in 1..=4 {
+            s.set_last_seq(seq);
+            save(&s, &dir, 2).unwrap();
+        }
+        assert_eq!(list(&dir).unwrap().iter().map(|(seq, _)| *seq).collect::<Vec<_>>(), vec![3, 4]);
+
+        // the written snapshot isn't removed whatever the number to keep
+        s.set_last_seq(5);
+        let path = save(&s, &dir, 0).unwrap();
+        assert_eq!(list(&dir).unwrap(), vec![(5, path.clone())]);
+        assert_eq!(load(&path).unwrap().last_seq(), 5);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,594 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::mutation::Mutation;
+
+// write-ahead log of the mutations.
+// every record is: payload length u32, crc32 of the payload u32, payload.
+// payload is: sequence number u64, unix time in milliseconds i64,
+// mutation json or {"type":"reload"} of the reload marker
+pub struct Wal {
+    file: File,
+    policy: FsyncPolicy,
+    next_seq: u64,
+    // offset of the end of the last record, and of its start for Wal::revert
+    len: u64,
+    last_start: u64,
+    // set when a failed append couldn't be cut off,
+    // the records appended after it would be lost on the next open
+    broken: bool,
+
+    // set when there are appended records not synced yet, for the interval policy
+    dirty: Arc<AtomicBool>,
+    // dropped with the log, that stops the fsync thread of the interval policy
+    _stop: Option<mpsc::Sender<()>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FsyncPolicy {
+    // fsync after every record
+    Always,
+    // fsync at most once per interval, in the background
+    Interval(Duration),
+    // leave it to the os
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = ();
+
+    // "always", "never" or the positive interval in milliseconds
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(FsyncPolicy::Always),
+            "never" => Ok(FsyncPolicy::Never),
+            ms => match ms.parse() {
+                Ok(0) | Err(_) => Err(()),
+                Ok(ms) => Ok(FsyncPolicy::Interval(Duration::from_millis(ms))),
+            },
+        }
+    }
+}
+
+pub struct Record {
+    pub seq: u64,
+    // unix time in milliseconds when the mutation was accepted
+    pub time: i64,
+    pub entry: Entry,
+}
+
+pub enum Entry {
+    Mutation(Mutation),
+    // the data was replaced by a reload, the mutations before
+    // the marker were of the replaced data
+    Reload,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Marker {
+    Reload,
+}
+
+const RECORD_HEADER: usize = 8;
+const PAYLOAD_HEADER: usize = 16;
+
+impl Wal {
+    // opens the log for appending and reads all the records in it.
+    // a torn or corrupted tail left by a crash is truncated,
+    // a corrupted record followed by more data is an error
+    pub fn open(path: &Path, policy: FsyncPolicy) -> io::Result<(Self, Vec<Record>)> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        let (records, ends) = read_records(&mut file)?;
+        let valid_len = ends.last().copied().unwrap_or(0);
+
+        if valid_len < file.metadata()?.len() {
+            warn!(
+                "wal: truncating corrupted tail of {} at {}",
+                path.display(),
+                valid_len
+            );
+            file.set_len(valid_len)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::Start(valid_len))?;
+
+        let next_seq = records.last().map(|r| r.seq + 1).unwrap_or(1);
+
+        let dirty = Arc::new(AtomicBool::new(false));
+        let mut stop = None;
+
+        if let FsyncPolicy::Interval(interval) = policy {
+            let file = file.try_clone()?;
+            let dirty = dirty.clone();
+            let (tx, rx) = mpsc::channel::<()>();
+            stop = Some(tx);
+
+            // syncs the last records and exits when the log is dropped
+            thread::spawn(move || loop {
+                let stopped = rx.recv_timeout(interval) == Err(RecvTimeoutError::Disconnected);
+                if dirty.swap(false, Ordering::AcqRel) {
+                    if let Err(e) = file.sync_data() {
+                        error!("wal: fsync error: {}", e);
+                    }
+                }
+                if stopped {
+                    break;
+                }
+            });
+        }
+
+        let wal = Wal {
+            file,
+            policy,
+            next_seq,
+            len: valid_len,
+            last_start: valid_len,
+            broken: false,
+            dirty,
+            _stop: stop,
+        };
+
+        Ok((wal, records))
+    }
+
+    // fsyncs the appended records regardless of the policy, e.g. before exiting
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    // makes the next record follow the sequence number,
+    // for the storage which has mutations not written to this log
+    pub fn skip_to(&mut self, seq: u64) {
+        if self.next_seq <= seq {
+            self.next_seq = seq + 1;
+        }
+    }
+
+    // sequence number of the next appended record
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    // appends the mutation, returns its sequence number
+    pub fn append(&mut self, mutation: &Mutation) -> io::Result<u64> {
+        self.append_json(mutation)
+    }
+
+    // appends the marker of the reload of the data, returns its sequence number
+    pub fn append_reload(&mut self) -> io::Result<u64> {
+        self.append_json(&Marker::Reload)
+    }
+
+    fn append_json(&mut self, value: &impl Serialize) -> io::Result<u64> {
+        if self.broken {
+            return Err(io::Error::other("wal: the log ends with a torn record"));
+        }
+
+        let seq = self.next_seq;
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or(0);
+
+        let mut payload = Vec::with_capacity(128);
+        payload.extend_from_slice(&seq.to_le_bytes());
+        payload.extend_from_slice(&time.to_le_bytes());
+        serde_json::to_writer(&mut payload, value)?;
+
+        let mut buf = Vec::with_capacity(RECORD_HEADER + payload.len());
+        buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        buf.extend_from_slice(&payload);
+
+        // a single write, so a crash tears at most the last record.
+        // a failed write or fsync is cut off, so the next record
+        // doesn't follow a torn one
+        let start = self.len;
+        if let Err(e) = self.write(&buf) {
+            if let Err(e) = self.truncate(start) {
+                error!("wal: failed to cut off the torn record: {}", e);
+                self.broken = true;
+            }
+            return Err(e);
+        }
+
+        self.next_seq += 1;
+        self.last_start = start;
+        self.len = start + buf.len() as u64;
+
+        Ok(seq)
+    }
+
+    // removes the last appended record, e.g. when the mutation
+    // failed to apply to the storage after it was logged
+    pub fn revert(&mut self, seq: u64) -> io::Result<()> {
+        if seq + 1 != self.next_seq || self.last_start == self.len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("wal: {} is not the last appended record", seq),
+            ));
+        }
+
+        if let Err(e) = self.truncate(self.last_start) {
+            self.broken = true;
+            return Err(e);
+        }
+        self.next_seq -= 1;
+        self.len = self.last_start;
+
+        Ok(())
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.file.write_all(buf)?;
+
+        match self.policy {
+            FsyncPolicy::Always => self.file.sync_data()?,
+            FsyncPolicy::Interval(_) => self.dirty.store(true, Ordering::Release),
+            FsyncPolicy::Never => (),
+        }
+
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)?;
+        self.file.seek(SeekFrom::Start(len))?;
+        Ok(())
+    }
+}
+
+// reads the valid records of the log without opening it for appending
+pub fn read(path: &Path) -> io::Result<Vec<Record>> {
+    let mut file = File::open(path)?;
+    Ok(read_records(&mut file)?.0)
+}
+
+// the mutations of the records after the sequence number, to replay them to the
+// data as of it. the mutations on the two sides of a reload were applied to
+// different data, so the log isn't replayed across a reload marker
+pub fn replay_after(records: &[Record], seq: u64) -> Result<Vec<(u64, &Mutation)>, String> {
+    let mut replay = Vec::new();
+
+    for record in records.iter().filter(|r| r.seq > seq) {
+        match &record.entry {
+            Entry::Mutation(mutation) => replay.push((record.seq, mutation)),
+            Entry::Reload => {
+                return Err(format!(
+                    "the data was reloaded at {}, the log after {} can't be replayed \
+                     across the reload without the snapshot of the reloaded data",
+                    record.seq, seq
+                ))
+            }
+        }
+    }
+
+    Ok(replay)
+}
+
+// cuts the log after the last record with the sequence number up to seq,
+// the corrupted tail is cut as well
+pub fn truncate_after(path: &Path, seq: u64) -> io::Result<()> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+
+    let (records, ends) = read_records(&mut file)?;
+    let len = records
+        .iter()
+        .zip(&ends)
+        .take_while(|(r, _)| r.seq <= seq)
+        .last()
+        .map(|(_, end)| *end)
+        .unwrap_or(0);
+
+    file.set_len(len)?;
+    file.sync_all()
+}
+
+// reads the records until the end of the file or the first invalid record,
+// returns the records and the file offsets where each of them ends.
+// only the last record may be invalid, that's a write torn by a crash.
+// an invalid record followed by more data means the log is corrupted,
+// dropping the records after it would silently lose the mutations
+fn read_records(file: &mut File) -> io::Result<(Vec<Record>, Vec<u64>)> {
+    let mut data = Vec::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_end(&mut data)?;
+
+    let mut records = Vec::new();
+    let mut ends = Vec::new();
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let end = match decode(&data[pos..]) {
+            Decoded::Record(record, len) => {
+                records.push(record);
+                pos + len
+            }
+            // a crash tears only the last record, so a record cut by the end
+            // of the file with valid records in its bytes has a corrupted length
+            Decoded::Torn if !has_record(&data[pos + 1..]) => break,
+            Decoded::Torn => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("wal: corrupted record length at offset {} followed by more records", pos),
+                ))
+            }
+            Decoded::Invalid(len) if pos + len >= data.len() => break,
+            Decoded::Invalid(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("wal: corrupted record at offset {} followed by more records", pos),
+                ))
+            }
+        };
+
+        pos = end;
+        ends.push(pos as u64);
+    }
+
+    Ok((records, ends))
+}
+
+// whether a valid record starts anywhere in the data
+fn has_record(data: &[u8]) -> bool {
+    (0..data.len()).any(|pos| matches!(decode(&data[pos..]), Decoded::Record(..)))
+}
+
+enum Decoded {
+    // the record and its length with the header
+    Record(Record, usize),
+    // the record is cut by the end of the file
+    Torn,
+    // the record of the length doesn't pass the checks
+    Invalid(usize),
+}
+
+fn decode(data: &[u8]) -> Decoded {
+    if data.len() < RECORD_HEADER {
+        return Decoded::Torn;
+    }
+
+    let len = u32::from_le_bytes(data[0..4].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(data[4..8].try_into().unwrap());
+
+    if data.len() - RECORD_HEADER < len {
+        return Decoded::Torn;
+    }
+    let size = RECORD_HEADER + len;
+    if len < PAYLOAD_HEADER {
+        return Decoded::Invalid(size);
+    }
+
+    let payload = &data[RECORD_HEADER..size];
+    if crc32fast::hash(payload) != crc {
+        return Decoded::Invalid(size);
+    }
+
+    let json = &payload[PAYLOAD_HEADER..];
+    let entry = match serde_json::from_slice(json) {
+        Ok(mutation) => Entry::Mutation(mutation),
+        Err(_) => match serde_json::from_slice(json) {
+            Ok(Marker::Reload) => Entry::Reload,
+            Err(_) => return Decoded::Invalid(size),
+        },
+    };
+
+    let record = Record {
+        seq: u64::from_le_bytes(payload[0..8].try_into().unwrap()),
+        time: i64::from_le_bytes(payload[8..16].try_into().unwrap()),
+        entry,
+    };
+
+    Decoded::Record(record, size)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        model,
+        storage::{MemStorage, Storage},
+    };
+
+    fn path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("hlcup-wal-{}-{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn mutations() -> Vec<Mutation> {
+        vec![
+            Mutation::NewUser(model::UserJSON {
+                id: 1,
+                email: "ann@mail.ru".to_string(),
+                first_name: "Ann".to_string(),
+                last_name: "Li".to_string(),
+                gender: "f".to_string(),
+                birth_date: 0,
+            }),
+            Mutation::NewLocation(model::LocationJSON {
+                id: 2,
+                distance: 10,
+                city: "Paris".to_string(),
+                country: "France".to_string(),
+                place: "Louvre".to_string(),
+            }),
+            Mutation::NewVisit(model::VisitJSON {
+                id: 3,
+                user: 1,
+                location: 2,
+                mark: 5,
+                visited_at: 100,
+            }),
+        ]
+    }
+
+    fn write(path: &Path, mutations: &[Mutation]) -> Vec<u64> {
+        let (mut wal, _) = Wal::open(path, FsyncPolicy::Never).unwrap();
+        mutations.iter().map(|m| wal.append(m).unwrap()).collect()
+    }
+
+    #[test]
+    fn replays_the_appended_records() {
+        let path = path("replay");
+        assert_eq!(write(&path, &mutations()), vec![1, 2, 3]);
+
+        let (mut wal, records) = Wal::open(&path, FsyncPolicy::Never).unwrap();
+        assert_eq!(records.iter().map(|r| r.seq).collect::<Vec<_>>(), vec![1, 2, 3]);
+
+        let mut s = MemStorage::new();
+        for (_, mutation) in replay_after(&records, 0).unwrap() {
+            mutation.apply(&mut s).unwrap();
+        }
+        assert_eq!(s.user(1).unwrap().email, "ann@mail.ru");
+        assert_eq!(s.visit(3).unwrap().location, 2);
+
+        // the numbering continues after the reopen
+        assert_eq!(wal.append(&mutations()[0]).unwrap(), 4);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn truncates_a_torn_tail() {
+        let path = path("torn");
+        write(&path, &mutations()[..2]);
+        let valid_len = fs::metadata(&path).unwrap().len();
+
+        // a record cut in the middle of its payload
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&100u32.to_le_bytes());
+        buf.extend_from_slice(&[0; 20]);
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(&buf).unwrap();
+
+        let (mut wal, records) = Wal::open(&path, FsyncPolicy::Never).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(fs::metadata(&path).unwrap().len(), valid_len);
+
+        assert_eq!(wal.append(&mutations()[2]).unwrap(), 3);
+        assert_eq!(read(&path).unwrap().len(), 3);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_a_corrupted_middle_record() {
+        let path = path("middle");
+        write(&path, &mutations());
+        let len = fs::metadata(&path).unwrap().len();
+
+        // a byte of the payload of the first record
+        let mut data = fs::read(&path).unwrap();
+        data[RECORD_HEADER + PAYLOAD_HEADER + 2] ^= 0xff;
+        fs::write(&path, &data).unwrap();
+
+        let err = Wal::open(&path, FsyncPolicy::Never).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(read(&path).is_err());
+        // the records after it are kept
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+
+        // the corrupted last record is a torn tail
+        data[RECORD_HEADER + PAYLOAD_HEADER + 2] ^= 0xff;
+        let last = data.len() - 2;
+        data[last] ^= 0xff;
+        fs::write(&path, &data).unwrap();
+        assert_eq!(read(&path).unwrap().len(), 2);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn replays_up_to_the_reload() {
+        let path = path("reload");
+        let (mut wal, _) = Wal::open(&path, FsyncPolicy::Never).unwrap();
+        wal.append(&mutations()[0]).unwrap();
+        assert_eq!(wal.next_seq(), 2);
+        assert_eq!(wal.append_reload().unwrap(), 2);
+        wal.append(&mutations()[1]).unwrap();
+        wal.append(&mutations()[2]).unwrap();
+
+        let records = read(&path).unwrap();
+        assert!(matches!(records[1].entry, Entry::Reload));
+
+        assert!(replay_after(&records, 0).unwrap_err().contains("reloaded at 2"));
+        assert!(replay_after(&records, 1).is_err());
+        let replay = replay_after(&records, 2).unwrap();
+        assert_eq!(replay.iter().map(|(seq, m)| (*seq, m.kind())).collect::<Vec<_>>(), vec![(3, "new_location"), (4, "new_visit")]);
+        assert!(replay_after(&records, 4).unwrap().is_empty());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_a_corrupted_middle_length() {
+        let path = path("length");
+        write(&path, &mutations());
+        let len = fs::metadata(&path).unwrap().len();
+
+        // the length of the first record runs past the end of the file
+        let mut data = fs::read(&path).unwrap();
+        data[2] = 0x7f;
+        fs::write(&path, &data).unwrap();
+
+        let err = Wal::open(&path, FsyncPolicy::Never).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(read(&path).is_err());
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn parses_the_fsync_policy() {
+        assert_eq!("always".parse(), Ok(FsyncPolicy::Always));
+        assert_eq!("never".parse(), Ok(FsyncPolicy::Never));
+        assert_eq!("100".parse(), Ok(FsyncPolicy::Interval(Duration::from_millis(100))));
+        assert!("0".parse::<FsyncPolicy>().is_err());
+        assert!("-1".parse::<FsyncPolicy>().is_err());
+    }
+
+    #[test]
+    fn reverts_the_last_record() {
+        let path = path("revert");
+        let (mut wal, _) = Wal::open(&path, FsyncPolicy::Never).unwrap();
+
+        wal.append(&mutations()[0]).unwrap();
+        let len = fs::metadata(&path).unwrap().len();
+        let seq = wal.append(&mutations()[1]).unwrap();
+
+        assert!(wal.revert(seq - 1).is_err());
+        wal.revert(seq).unwrap();
+        assert!(wal.revert(seq).is_err());
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+
+        assert_eq!(wal.append(&mutations()[2]).unwrap(), seq);
+        let records = read(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        assert!(matches!(&records[1].entry, Entry::Mutation(m) if m.kind() == "new_visit"));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,45 @@
+use std::time::Instant;
+
+use crate::{model, storage::Storage};
+
+// runs the visits and the average mark queries of all the users and locations once,
+// so the indexes and the files of the mmap storage are in the memory
+// before the first requests of the rating run
+pub fn run(s: &dyn Storage) {
+    let start = Instant::now();
+
+    let user_params = model::UserVisitsParams {
+        from_date: None,
+        to_date: None,
+        country: None,
+        to_distance: None,
+    };
+    let location_params = model::LocationAvgParams {
+        from_date: None,
+        to_date: None,
+        from_age: None,
+        to_age: None,
+        gender: None,
+    };
+
+    let mut visits = 0;
+    let mut queries = 0;
+
+    for id in s.user_ids() {
+        if let Ok(v) = s.user_visits(id, &user_params) {
+            visits += v.len();
+        }
+        queries += 1;
+    }
+    for id in s.location_ids() {
+        let _ = s.location_avg(id, &location_params);
+        queries += 1;
+    }
+
+    info!(
+        "warmup: {} queries, {} visits in {:?}",
+        queries,
+        visits,
+        start.elapsed()
+    );
+}